@@ -0,0 +1,99 @@
+use actix_web::web;
+use async_trait::async_trait;
+use fizzy_commons::shared_structs::MessageRequest;
+use crate::outbox::is_standalone;
+use crate::redis::get_user_message;
+use crate::sender::get_sender;
+use crate::structs::{InboundMessage, Message, StandardResponse};
+use crate::tools::{find_message_type, get_message_content};
+use crate::webchat::{WebChatChannel, WEBCHAT_PREFIX};
+
+// Channel customers talk to the flow through, normalizing their messages and rendering ours
+#[async_trait(?Send)]
+pub trait Channel: Send + Sync {
+    fn name(&self) -> &'static str;
+
+    // Stored message received from the user, in the format handled by the flow
+    fn get_message(&self, user_id: &str, message_id: &str) -> Result<InboundMessage, String>;
+
+    // Renders the message in the channel format and delivers it
+    async fn send(&self, message: &MessageRequest) -> Result<StandardResponse, String>;
+
+    // Whether this system continues the flow once a step message is sent, instead of whatsapp-manager
+    fn continues_flow(&self) -> bool;
+
+    // Whether free-form messages are only accepted for a while after the user's last message
+    fn has_service_window(&self) -> bool;
+}
+
+pub struct WhatsAppChannel;
+
+#[async_trait(?Send)]
+impl Channel for WhatsAppChannel {
+    fn name(&self) -> &'static str {
+        "whatsapp"
+    }
+
+    fn get_message(&self, user_id: &str, message_id: &str) -> Result<InboundMessage, String> {
+        let event = match get_user_message(message_id, user_id) {
+            Ok(event) => event,
+            Err(err) => return Err(format!("Error obtaining user message: {}", err)),
+        };
+
+        // Stored event may hold several messages, only the referenced one is handled
+        let message = event.find_message(message_id);
+
+        if message.is_none() {
+            return Err(format!("Message {} not found in stored event", message_id))
+        }
+
+        let mut inbound = normalize_message(message.unwrap());
+        inbound.contact_name = event.contact_name();
+
+        Ok(inbound)
+    }
+
+    async fn send(&self, message: &MessageRequest) -> Result<StandardResponse, String> {
+        // Senders make blocking HTTP calls, they run in the blocking pool instead of the actix worker
+        let message: MessageRequest = serde_json::from_value(serde_json::to_value(message).unwrap()).unwrap();
+
+        match web::block(move || get_sender().send(&message)).await {
+            Ok(res) => res,
+            Err(err) => Err(format!("Error running message sender: {}", err)),
+        }
+    }
+
+    fn continues_flow(&self) -> bool {
+        is_standalone()
+    }
+
+    fn has_service_window(&self) -> bool {
+        true
+    }
+}
+
+pub fn normalize_message(message: &Message) -> InboundMessage {
+    InboundMessage {
+        id: message.id.clone(),
+        user_id: message.from.clone(),
+        message_type: find_message_type(message),
+        channel_type: message.message_type.clone(),
+        content: get_message_content(message),
+        image_id: message.image.as_ref().map(|image| image.id.clone()),
+        // Meta timestamps are in seconds
+        timestamp: message.timestamp.parse::<u64>().map(|timestamp| timestamp * 1000).unwrap_or(0),
+        contact_name: None,
+    }
+}
+
+// Channel of the user, web chat users are prefixed and everyone else is a WhatsApp phone number
+pub fn get_channel(user_id: &str) -> &'static dyn Channel {
+    static WHATSAPP: WhatsAppChannel = WhatsAppChannel;
+    static WEBCHAT: WebChatChannel = WebChatChannel;
+
+    if user_id.starts_with(WEBCHAT_PREFIX) {
+        &WEBCHAT
+    } else {
+        &WHATSAPP
+    }
+}
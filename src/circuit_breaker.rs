@@ -0,0 +1,170 @@
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, Instant};
+use serde::Serialize;
+use crate::tools::get_env_setting;
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+enum BreakerState {
+    Closed,
+    Open(Instant),
+    HalfOpen, // Open period elapsed, a single probe request is allowed through
+}
+
+#[derive(Serialize, Debug)]
+pub struct BreakerStatus {
+    pub name: String,
+    pub state: String, // closed, open or half_open
+    pub consecutive_failures: u32,
+}
+
+// Stops calling an upstream after consecutive failures, probing it again once the open period is over
+pub struct CircuitBreaker {
+    name: String,
+    failure_threshold: u32,
+    open_duration: Duration,
+    state: Mutex<(BreakerState, u32)>, // State along with the consecutive failures
+}
+
+impl CircuitBreaker {
+    pub fn new(name: &str, failure_threshold: u32, open_duration: Duration) -> CircuitBreaker {
+        CircuitBreaker {
+            name: name.to_string(),
+            failure_threshold,
+            open_duration,
+            state: Mutex::new((BreakerState::Closed, 0)),
+        }
+    }
+
+    pub fn from_env(name: &str) -> CircuitBreaker {
+        let failure_threshold = get_env_setting("CIRCUIT_BREAKER_FAILURES", 5) as u32;
+        let open_duration = Duration::from_secs(get_env_setting("CIRCUIT_BREAKER_OPEN_SECONDS", 30));
+
+        CircuitBreaker::new(name, failure_threshold, open_duration)
+    }
+
+    // Runs the upstream call if the breaker allows it, client errors(4xx) don't count as upstream failures
+    pub fn call<T>(&self, request: impl FnOnce() -> Result<T, ureq::Error>) -> Result<T, String> {
+        if !self.allow_request() {
+            return Err(format!("Circuit breaker for {} is open", self.name))
+        }
+
+        match request() {
+            Ok(response) => {
+                self.record_success();
+                Ok(response)
+            }
+            Err(ureq::Error::Status(code, response)) if code < 500 => {
+                self.record_success();
+                Err(format!("{} answered with status {}: {}", self.name, code, response.into_string().unwrap_or_default()))
+            }
+            Err(err) => {
+                self.record_failure();
+                Err(format!("Error calling {}: {}", self.name, err))
+            }
+        }
+    }
+
+    fn allow_request(&self) -> bool {
+        let mut state = self.state.lock().unwrap();
+
+        match state.0 {
+            BreakerState::Closed => true,
+            BreakerState::Open(since) if since.elapsed() >= self.open_duration => {
+                info!("Circuit breaker for {} half open, probing upstream", self.name);
+                state.0 = BreakerState::HalfOpen;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn record_success(&self) {
+        let mut state = self.state.lock().unwrap();
+
+        if state.0 != BreakerState::Closed {
+            info!("Circuit breaker for {} closed", self.name);
+        }
+
+        *state = (BreakerState::Closed, 0);
+    }
+
+    fn record_failure(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.1 += 1;
+
+        if state.0 == BreakerState::HalfOpen || state.1 >= self.failure_threshold {
+            error!("Circuit breaker for {} open after {} consecutive failures", self.name, state.1);
+            state.0 = BreakerState::Open(Instant::now());
+        }
+    }
+
+    pub fn status(&self) -> BreakerStatus {
+        let state = self.state.lock().unwrap();
+
+        BreakerStatus {
+            name: self.name.clone(),
+            state: match state.0 {
+                BreakerState::Closed => "closed",
+                BreakerState::Open(since) if since.elapsed() >= self.open_duration => "half_open",
+                BreakerState::Open(_) => "open",
+                BreakerState::HalfOpen => "half_open",
+            }.to_string(),
+            consecutive_failures: state.1,
+        }
+    }
+}
+
+pub fn whatsapp_manager_breaker() -> &'static CircuitBreaker {
+    static BREAKER: OnceLock<CircuitBreaker> = OnceLock::new();
+    BREAKER.get_or_init(|| CircuitBreaker::from_env("whatsapp-manager"))
+}
+
+pub fn graph_api_breaker() -> &'static CircuitBreaker {
+    static BREAKER: OnceLock<CircuitBreaker> = OnceLock::new();
+    BREAKER.get_or_init(|| CircuitBreaker::from_env("graph-api"))
+}
+
+// Status of every upstream breaker, for the health endpoint
+pub fn get_breakers_status() -> Vec<BreakerStatus> {
+    vec![whatsapp_manager_breaker().status(), graph_api_breaker().status()]
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+    use crate::circuit_breaker::CircuitBreaker;
+
+    fn failing_request() -> Result<(), ureq::Error> {
+        Err(ureq::Error::Status(503, ureq::Response::new(503, "Service Unavailable", "").unwrap()))
+    }
+
+    #[test]
+    fn opens_after_consecutive_failures() {
+        let breaker = CircuitBreaker::new("test", 2, Duration::from_secs(60));
+
+        assert!(breaker.call(failing_request).is_err());
+        assert_eq!(breaker.status().state, "closed");
+        assert!(breaker.call(failing_request).is_err());
+        assert_eq!(breaker.status().state, "open");
+
+        // Requests are rejected without calling the upstream while open
+        let res = breaker.call(|| -> Result<(), ureq::Error> { panic!("upstream called with open breaker") });
+        assert!(res.unwrap_err().contains("is open"));
+    }
+
+    #[test]
+    fn half_open_probe_closes_or_reopens() {
+        let breaker = CircuitBreaker::new("test", 1, Duration::from_millis(0));
+
+        assert!(breaker.call(failing_request).is_err());
+        assert_eq!(breaker.status().state, "half_open");
+
+        // Failed probe opens the breaker again
+        assert!(breaker.call(failing_request).is_err());
+        assert_eq!(breaker.status().consecutive_failures, 2);
+
+        assert!(breaker.call(|| Ok(())).is_ok());
+        assert_eq!(breaker.status().state, "closed");
+        assert_eq!(breaker.status().consecutive_failures, 0);
+    }
+}
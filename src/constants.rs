@@ -1,15 +1,35 @@
 use std::string::ToString;
 use enum_iterator::{all, cardinality, first, last, next, previous, reverse_all, Sequence};
-use fizzy_commons::shared_structs::{ListMessage, MessageContent, MessageRequest};
+use fizzy_commons::shared_structs::{ButtonMessage, Choice, ListMessage, MessageContent, MessageRequest};
 use serde::de::Unexpected::Str;
+use serde::{Deserialize, Serialize};
 use crate::constants::FlowStatus::{BrandModalSent, BrandSelected, IdentificationRequestSent, ModelModalSent, ModelSelected, PartDescriptionProvided, RequestAccepted};
 
-use crate::constants::FlowStatusId::{BrandModalSentId, BrandSelectedId, FlowStartedId, IdentificationProvidedId, IdentificationRequestSentId, ModelModalSentId, ModelSelectedId, PartDescriptionProvidedId, PartDescriptionRequestedId, RequestAcceptedId};
-use crate::constants::MessageType::{ListSelection, NoResponse, PlainText, PlainTextAndImage};
-use crate::structs::{StepDefinition};
+use crate::constants::FlowStatusId::{BrandModalSentId, BrandSelectedId, EditFieldListSentId, EditFieldSelectedId, FlowStartedId, IdentificationProvidedId, IdentificationRequestSentId, ModelModalSentId, ModelSelectedId, PartDescriptionProvidedId, PartDescriptionRequestedId, RequestAcceptedId, SummaryReviewedId, AgentHandoffId, PartClassifiedId, QuotesSentId, QuoteSelectedId, GarageListSentId, GarageVehicleSelectedId, AddPartPromptSentId, AddPartSelectedId};
+use crate::constants::MessageType::{ButtonSelection, ListSelection, NoResponse, PlainText, PlainTextAndImage};
+use crate::structs::{MessageTemplate, StepDefinition};
 
 pub const SYSTEM_ID: u8 = 3;
 pub const PART_CLASSIFICATION_SYSTEM_ID: u8 = 4;
+pub const SUPPLIER_SYSTEM_ID: u8 = 6;
+
+// Schema version of the PartRequestSubmitted event, increase on breaking changes
+pub const PART_REQUEST_SUBMITTED_VERSION: u16 = 1;
+
+// VINs have 17 characters, any other identifier provided is a plate
+pub const VIN_LENGTH: usize = 17;
+
+// Message the user can send at any step to talk with an agent
+pub const AGENT_COMMAND: &str = "asesor";
+
+// Prefix of the ticket numbers customers use to refer to their requests
+pub const TICKET_PREFIX: &str = "SOL-";
+
+// Template language used when the customer profile doesn't set one
+pub const DEFAULT_LANGUAGE: &str = "es";
+
+// Template sent by the scheduler to remind inactive users of their request
+pub const REMINDER_TEMPLATE_NAME: &str = "request_reminder";
 
 #[derive(Debug)]
 pub enum FlowStatusId {
@@ -23,6 +43,18 @@ pub enum FlowStatusId {
     PartDescriptionRequestedId = 8,
     PartDescriptionProvidedId = 9,
     RequestAcceptedId = 10,
+    RequestSummarySentId = 11,
+    SummaryReviewedId = 12,
+    EditFieldListSentId = 13,
+    EditFieldSelectedId = 14,
+    AgentHandoffId = 15,
+    PartClassifiedId = 16,
+    QuotesSentId = 17,
+    QuoteSelectedId = 18,
+    GarageListSentId = 19,
+    GarageVehicleSelectedId = 20,
+    AddPartPromptSentId = 21,
+    AddPartSelectedId = 22,
 }
 
 impl FlowStatusId {
@@ -39,6 +71,18 @@ impl FlowStatusId {
             8 => FlowStatusId::PartDescriptionRequestedId,
             9 => FlowStatusId::PartDescriptionProvidedId,
             10 => FlowStatusId::RequestAcceptedId,
+            11 => FlowStatusId::RequestSummarySentId,
+            12 => FlowStatusId::SummaryReviewedId,
+            13 => FlowStatusId::EditFieldListSentId,
+            14 => FlowStatusId::EditFieldSelectedId,
+            15 => FlowStatusId::AgentHandoffId,
+            16 => FlowStatusId::PartClassifiedId,
+            17 => FlowStatusId::QuotesSentId,
+            18 => FlowStatusId::QuoteSelectedId,
+            19 => FlowStatusId::GarageListSentId,
+            20 => FlowStatusId::GarageVehicleSelectedId,
+            21 => FlowStatusId::AddPartPromptSentId,
+            22 => FlowStatusId::AddPartSelectedId,
             _ => panic!("Value not found"),
         }
     }
@@ -56,6 +100,18 @@ pub enum FlowStatus {
     PartDescriptionRequested = 8,
     PartDescriptionProvided = 9,
     RequestAccepted = 10,
+    RequestSummarySent = 11,
+    SummaryReviewed = 12,
+    EditFieldListSent = 13,
+    EditFieldSelected = 14,
+    AgentHandoff = 15,
+    PartClassified = 16,
+    QuotesSent = 17,
+    QuoteSelected = 18,
+    GarageListSent = 19,
+    GarageVehicleSelected = 20,
+    AddPartPromptSent = 21,
+    AddPartSelected = 22,
 }
 
 
@@ -80,7 +136,7 @@ impl FlowStatus{
             to: vec![],
             message_type: String::from("list"),
             content: MessageContent {
-                body: Some("Selecciona la marca del vehiculo.".to_string()),
+                body: Some("Hola {name}, selecciona la marca del vehiculo.".to_string()),
                 list: Some(ListMessage{
                     title: "Marcas".to_string(),
                     choices: vec![],
@@ -180,12 +236,163 @@ impl FlowStatus{
             to: vec![],
             message_type: String::from("text"),
             content: MessageContent {
-                body: Some("Se recibio la solicitud de repuesto exitosamente, lo estaremos contactando una vez encontremos el repuesto buscado.".to_string()),
+                body: Some("Gracias {name}, se recibio la solicitud de repuesto {ticket} exitosamente, lo estaremos contactando una vez encontremos el repuesto buscado.".to_string()),
+                list: None,
+                buttons: None,
+            },
+        };
+
+        let REQUEST_SUMMARY_SENT_MESSAGE: MessageRequest = MessageRequest{
+            system_id: SYSTEM_ID,
+            to: vec![],
+            message_type: String::from("button"),
+            content: MessageContent {
+                body: Some("Resumen de la solicitud:\n{}\n\nConfirma si los datos son correctos.".to_string()),
+                list: None,
+                buttons: Some(ButtonMessage{
+                    title: "Resumen".to_string(),
+                    choices: vec![
+                        Choice{ id: "confirm-id".to_string(), value: "Confirmar".to_string() },
+                        Choice{ id: "edit-id".to_string(), value: "Editar".to_string() },
+                    ],
+                }),
+            },
+        };
+
+        let SUMMARY_REVIEWED_MESSAGE: MessageRequest = MessageRequest{
+            system_id: SYSTEM_ID,
+            to: vec![],
+            message_type: String::from("text"),
+            content: MessageContent {
+                body: Some("Solicitud confirmada.".to_string()),
+                list: None,
+                buttons: None,
+            },
+        };
+
+        let EDIT_FIELD_LIST_SENT_MESSAGE: MessageRequest = MessageRequest{
+            system_id: SYSTEM_ID,
+            to: vec![],
+            message_type: String::from("list"),
+            content: MessageContent {
+                body: Some("Selecciona el dato que deseas corregir.".to_string()),
+                list: Some(ListMessage{
+                    title: "Datos".to_string(),
+                    choices: vec![
+                        Choice{ id: "brand-field".to_string(), value: "Marca".to_string() },
+                        Choice{ id: "model-field".to_string(), value: "Modelo".to_string() },
+                        Choice{ id: "identification-field".to_string(), value: "Patente o VIN".to_string() },
+                        Choice{ id: "description-field".to_string(), value: "Descripcion".to_string() },
+                    ],
+                }),
+                buttons: None,
+            },
+        };
+
+        let AGENT_HANDOFF_MESSAGE: MessageRequest = MessageRequest{
+            system_id: SYSTEM_ID,
+            to: vec![],
+            message_type: String::from("text"),
+            content: MessageContent {
+                body: Some("Te estamos comunicando con un asesor, te respondera a la brevedad.".to_string()),
                 list: None,
                 buttons: None,
             },
         };
 
+        let PART_CLASSIFIED_MESSAGE: MessageRequest = MessageRequest{
+            system_id: SYSTEM_ID,
+            to: vec![],
+            message_type: String::from("text"),
+            content: MessageContent {
+                body: Some("Hemos identificado el repuesto de tu solicitud:\nCategoria: {category}\nNumero de parte: {part_number}\n\nTe contactaremos con las cotizaciones disponibles.".to_string()),
+                list: None,
+                buttons: None,
+            },
+        };
+
+        let QUOTES_SENT_MESSAGE: MessageRequest = MessageRequest{
+            system_id: SYSTEM_ID,
+            to: vec![],
+            message_type: String::from("list"),
+            content: MessageContent {
+                body: Some("Encontramos estas ofertas para tu repuesto:\n{}\n\nSelecciona la que prefieras.".to_string()),
+                list: Some(ListMessage{
+                    title: "Ofertas".to_string(),
+                    choices: vec![],
+                }),
+                buttons: None,
+            },
+        };
+
+        let QUOTE_SELECTED_MESSAGE: MessageRequest = MessageRequest{
+            system_id: SYSTEM_ID,
+            to: vec![],
+            message_type: String::from("text"),
+            content: MessageContent {
+                body: Some("Has seleccionado la oferta de {}. El proveedor se pondra en contacto contigo para coordinar la entrega.".to_string()),
+                list: None,
+                buttons: None,
+            },
+        };
+
+        let GARAGE_LIST_SENT_MESSAGE: MessageRequest = MessageRequest{
+            system_id: SYSTEM_ID,
+            to: vec![],
+            message_type: String::from("list"),
+            content: MessageContent {
+                body: Some("Hola {name}, selecciona el vehiculo de la solicitud.".to_string()),
+                list: Some(ListMessage{
+                    title: "Vehiculos".to_string(),
+                    choices: vec![],
+                }),
+                buttons: None,
+            },
+        };
+
+        let GARAGE_VEHICLE_SELECTED_MESSAGE: MessageRequest = MessageRequest{
+            system_id: SYSTEM_ID,
+            to: vec![],
+            message_type: String::from("text"),
+            content: MessageContent {
+                body: Some("Has seleccionado {}.".to_string()),
+                list: None,
+                buttons: None,
+            },
+        };
+
+        // Button titles are limited to 20 characters
+        let ADD_PART_PROMPT_SENT_MESSAGE: MessageRequest = MessageRequest{
+            system_id: SYSTEM_ID,
+            to: vec![],
+            message_type: String::from("button"),
+            content: MessageContent {
+                body: Some("Deseas agregar otro repuesto a la solicitud?".to_string()),
+                list: None,
+                buttons: Some(ButtonMessage{
+                    title: "Repuestos".to_string(),
+                    choices: vec![
+                        Choice{ id: "add-part-id".to_string(), value: "Agregar repuesto".to_string() },
+                        Choice{ id: "finish-id".to_string(), value: "Terminar".to_string() },
+                    ],
+                }),
+            },
+        };
+
+        // TEMPLATES SENT OUTSIDE THE CUSTOMER SERVICE WINDOW
+
+        let PART_CLASSIFIED_TEMPLATE: MessageTemplate = MessageTemplate{
+            name: "part_classified".to_string(),
+            language: DEFAULT_LANGUAGE.to_string(),
+            parameters: vec!["{body}".to_string()],
+        };
+
+        let QUOTES_SENT_TEMPLATE: MessageTemplate = MessageTemplate{
+            name: "quotes_available".to_string(),
+            language: DEFAULT_LANGUAGE.to_string(),
+            parameters: vec![],
+        };
+
         let flow_started_step:StepDefinition =  StepDefinition{
             required_response: None,
             validation_regex: Some(String::from("")),
@@ -193,25 +400,28 @@ impl FlowStatus{
             next_step: Some(BrandModalSentId),
             successful_response: Some(FLOW_STARTED_MESSAGE),
             data_origin: None,
+            template: None,
         };
 
 
         let brand_modal_sent_step: StepDefinition =  StepDefinition{
-            required_response: Some(PlainText),
+            required_response: Some(vec![PlainText]),
             validation_regex: Some(String::from("hola")),
 
             next_step: Some(BrandSelectedId),
             successful_response: Some(BRAND_MODAL_SENT_MESSAGE),
             data_origin: Some("makes".to_string()),
+            template: None,
         };
 
         let brand_selected_step:StepDefinition =  StepDefinition{
-            required_response: Some(ListSelection),
+            required_response: Some(vec![ListSelection]),
             validation_regex: Some("".to_string()),
 
             next_step: Some(ModelModalSentId),
             successful_response: Some(BRAND_SELECTED_MESSAGE),
             data_origin: None,
+            template: None,
         };
 
         let model_modal_sent_step: StepDefinition =  StepDefinition{
@@ -221,15 +431,17 @@ impl FlowStatus{
             next_step: Some(ModelSelectedId),
             successful_response: Some(MODEL_MODAL_SENT_MESSAGE),
             data_origin: Some("models:{}".to_string()),
+            template: None,
         };
 
         let model_selected_step:StepDefinition = StepDefinition{
-            required_response: Some(ListSelection),
+            required_response: Some(vec![ListSelection]),
             validation_regex: Some("".to_string()),
 
             next_step: Some(IdentificationRequestSentId),
             successful_response: Some(MODEL_SELECTED_MESSAGE),
             data_origin: Some("models:{}".to_string()),
+            template: None,
         };
 
         let identification_request_sent_step:StepDefinition =  StepDefinition{
@@ -239,15 +451,17 @@ impl FlowStatus{
             next_step: Some(IdentificationProvidedId),
             successful_response: Some(IDENTIFICATION_REQUEST_SENT_MESSAGE),
             data_origin: None,
+            template: None,
         };
 
         let identification_provided_step: StepDefinition =  StepDefinition{
-            required_response: Some(PlainText),
+            required_response: Some(vec![PlainText]),
             validation_regex: Some("([A-Za-z0-9]){17,19}|([A-Z0-9]{6})".to_string()),
 
             next_step: Some(PartDescriptionRequestedId),
             successful_response: Some(IDENTIFICATION_PROVIDED_MESSAGE),
             data_origin: None,
+            template: None,
         };
 
         let part_description_requested_step:StepDefinition =  StepDefinition{
@@ -257,15 +471,17 @@ impl FlowStatus{
             next_step: Some(PartDescriptionProvidedId),
             successful_response: Some(PART_DESCRIPTION_REQUESTED_MESSAGE),
             data_origin: None,
+            template: None,
         };
 
         let part_description_provided_step:StepDefinition =  StepDefinition{
-            required_response: Some(PlainTextAndImage),
+            required_response: Some(vec![PlainTextAndImage, PlainText]),
             validation_regex: Some("".to_string()),
 
-            next_step: Some(RequestAcceptedId),
+            next_step: Some(AddPartPromptSentId),
             successful_response: Some(PART_DESCRIPTION_PROVIDED_MESSAGE),
             data_origin: None,
+            template: None,
         };
 
 
@@ -276,6 +492,135 @@ impl FlowStatus{
             next_step: None,
             successful_response: Some(REQUEST_ACCEPTED_MESSAGE),
             data_origin: None,
+            template: None,
+        };
+
+        let request_summary_sent_step:StepDefinition =  StepDefinition{
+            required_response: None,
+            validation_regex: Some("".to_string()),
+
+            next_step: Some(SummaryReviewedId),
+            successful_response: Some(REQUEST_SUMMARY_SENT_MESSAGE),
+            data_origin: None,
+            template: None,
+        };
+
+        let summary_reviewed_step:StepDefinition =  StepDefinition{
+            required_response: Some(vec![ButtonSelection]),
+            validation_regex: Some("confirm-id|edit-id".to_string()),
+
+            next_step: Some(RequestAcceptedId),
+            successful_response: Some(SUMMARY_REVIEWED_MESSAGE),
+            data_origin: None,
+            template: None,
+        };
+
+        // Only reached from SummaryReviewed when the user chooses to edit the request
+        let edit_field_list_sent_step:StepDefinition =  StepDefinition{
+            required_response: Some(vec![ListSelection]),
+            validation_regex: Some("".to_string()),
+
+            next_step: Some(EditFieldSelectedId),
+            successful_response: Some(EDIT_FIELD_LIST_SENT_MESSAGE),
+            data_origin: None,
+            template: None,
+        };
+
+        // Handler replaces this step with the step of the field being edited
+        let edit_field_selected_step:StepDefinition =  StepDefinition{
+            required_response: Some(vec![ListSelection]),
+            validation_regex: Some(r"brand-field|model-field|identification-field|description-field|item-\d+".to_string()),
+
+            next_step: None,
+            successful_response: None,
+            data_origin: None,
+            template: None,
+        };
+
+        // Flow is paused while an agent handles the conversation, it's resumed by releasing the tracker
+        let agent_handoff_step:StepDefinition =  StepDefinition{
+            required_response: None,
+            validation_regex: Some("".to_string()),
+
+            next_step: None,
+            successful_response: Some(AGENT_HANDOFF_MESSAGE),
+            data_origin: None,
+            template: None,
+        };
+
+        // Sent by the classification system once the accepted request is processed
+        let part_classified_step:StepDefinition =  StepDefinition{
+            required_response: None,
+            validation_regex: Some("".to_string()),
+
+            next_step: None,
+            successful_response: Some(PART_CLASSIFIED_MESSAGE),
+            data_origin: None,
+            template: Some(PART_CLASSIFIED_TEMPLATE),
+        };
+
+        // Sent once suppliers quotes are attached to the tracker
+        let quotes_sent_step:StepDefinition =  StepDefinition{
+            required_response: None,
+            validation_regex: Some("".to_string()),
+
+            next_step: Some(QuoteSelectedId),
+            successful_response: Some(QUOTES_SENT_MESSAGE),
+            data_origin: None,
+            template: Some(QUOTES_SENT_TEMPLATE),
+        };
+
+        let quote_selected_step:StepDefinition =  StepDefinition{
+            required_response: Some(vec![ListSelection]),
+            validation_regex: Some("^quote-[a-f0-9-]+$".to_string()),
+
+            next_step: None,
+            successful_response: Some(QUOTE_SELECTED_MESSAGE),
+            data_origin: None,
+            template: None,
+        };
+
+        let add_part_prompt_sent_step:StepDefinition =  StepDefinition{
+            required_response: None,
+            validation_regex: Some("".to_string()),
+
+            next_step: Some(AddPartSelectedId),
+            successful_response: Some(ADD_PART_PROMPT_SENT_MESSAGE),
+            data_origin: None,
+            template: None,
+        };
+
+        // Handler replaces this step with another description request or the request summary
+        let add_part_selected_step:StepDefinition =  StepDefinition{
+            required_response: Some(vec![ButtonSelection]),
+            validation_regex: Some("add-part-id|finish-id".to_string()),
+
+            next_step: None,
+            successful_response: None,
+            data_origin: None,
+            template: None,
+        };
+
+        // Replaces the brand list when the customer has saved vehicles
+        let garage_list_sent_step: StepDefinition =  StepDefinition{
+            required_response: Some(vec![PlainText]),
+            validation_regex: Some(String::from("hola")),
+
+            next_step: Some(GarageVehicleSelectedId),
+            successful_response: Some(GARAGE_LIST_SENT_MESSAGE),
+            data_origin: None,
+            template: None,
+        };
+
+        // Handler goes back to the brand list when the user chooses another vehicle
+        let garage_vehicle_selected_step:StepDefinition =  StepDefinition{
+            required_response: Some(vec![ListSelection]),
+            validation_regex: Some("^(vehicle-[A-Za-z0-9]+|other-vehicle)$".to_string()),
+
+            next_step: Some(PartDescriptionRequestedId),
+            successful_response: Some(GARAGE_VEHICLE_SELECTED_MESSAGE),
+            data_origin: None,
+            template: None,
         };
 
         match self {
@@ -289,11 +634,27 @@ impl FlowStatus{
             FlowStatus::PartDescriptionRequested => part_description_requested_step,
             FlowStatus::PartDescriptionProvided => part_description_provided_step,
             FlowStatus::RequestAccepted => request_accepted_step,
+            FlowStatus::RequestSummarySent => request_summary_sent_step,
+            FlowStatus::SummaryReviewed => summary_reviewed_step,
+            FlowStatus::EditFieldListSent => edit_field_list_sent_step,
+            FlowStatus::EditFieldSelected => edit_field_selected_step,
+            FlowStatus::AgentHandoff => agent_handoff_step,
+            FlowStatus::PartClassified => part_classified_step,
+            FlowStatus::QuotesSent => quotes_sent_step,
+            FlowStatus::QuoteSelected => quote_selected_step,
+            FlowStatus::GarageListSent => garage_list_sent_step,
+            FlowStatus::GarageVehicleSelected => garage_vehicle_selected_step,
+            FlowStatus::AddPartPromptSent => add_part_prompt_sent_step,
+            FlowStatus::AddPartSelected => add_part_selected_step,
         }
 
 
     }
 
+    pub fn name(&self) -> String {
+        format!("{:?}", self)
+    }
+
     pub fn get_from_value(i: &String) -> FlowStatus {
         debug!("ingest: {}", i);
         let status_id = i.parse().unwrap();
@@ -309,19 +670,32 @@ impl FlowStatus{
             8 => FlowStatus::PartDescriptionRequested,
             9 => FlowStatus::PartDescriptionProvided,
             10 => FlowStatus::RequestAccepted,
+            11 => FlowStatus::RequestSummarySent,
+            12 => FlowStatus::SummaryReviewed,
+            13 => FlowStatus::EditFieldListSent,
+            14 => FlowStatus::EditFieldSelected,
+            15 => FlowStatus::AgentHandoff,
+            16 => FlowStatus::PartClassified,
+            17 => FlowStatus::QuotesSent,
+            18 => FlowStatus::QuoteSelected,
+            19 => FlowStatus::GarageListSent,
+            20 => FlowStatus::GarageVehicleSelected,
+            21 => FlowStatus::AddPartPromptSent,
+            22 => FlowStatus::AddPartSelected,
             _ => panic!("Value not found"),
         }
     }
 
 }
 
-#[derive(PartialEq, Debug)]
+#[derive(PartialEq, Debug, Clone, Copy, Serialize, Deserialize)]
 pub enum MessageType {
     PlainText,
     PlainTextAndImage,
     ListSelection,
     ButtonSelection,
-    NoResponse
+    NoResponse,
+    Unsupported, // Media, reactions and other messages the flow can't handle
 }
 
 #[derive(PartialEq)]
@@ -337,3 +711,41 @@ pub enum ResponseStatus{
 
 
 
+
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub enum TrackerState {
+    Active,
+    Completed,
+    Expired,
+    Abandoned,
+    Handoff,
+}
+
+impl TrackerState {
+    pub fn value(&self) -> &'static str {
+        match self {
+            TrackerState::Active => "active",
+            TrackerState::Completed => "completed",
+            TrackerState::Expired => "expired",
+            TrackerState::Abandoned => "abandoned",
+            TrackerState::Handoff => "handoff",
+        }
+    }
+}
+
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub enum StepOrigin {
+    User,
+    System,
+    Agent,
+}
+
+impl StepOrigin {
+    pub fn value(&self) -> &'static str {
+        match self {
+            StepOrigin::User => "user",
+            StepOrigin::System => "system",
+            StepOrigin::Agent => "agent",
+        }
+    }
+}
@@ -1,6 +1,9 @@
 use std::collections::HashMap;
-use crate::structs::{MessageLog, TrackerParam};
-use actix_web::{get, post, web, App, HttpResponse, HttpServer, Responder};
+use crate::structs::{AgentReply, Event, HandoffRelease, MessageLog, OutboxParams, PartClassification, QuotesRequest, TrackerParam, TrackerSearchParams};
+use std::future::{ready, Ready};
+use actix_web::{get, post, web, App, FromRequest, HttpRequest, HttpResponse, HttpServer, Responder};
+use actix_web::dev::Payload;
+use actix_web::error::InternalError;
 use actix_web::web::Query;
 use aws_config::meta::region::RegionProviderChain;
 use aws_config::SdkConfig;
@@ -15,6 +18,12 @@ mod structs;
 mod tools;
 mod constants;
 mod step_functions;
+mod scheduler;
+mod outbox;
+mod circuit_breaker;
+mod sender;
+mod channel;
+mod webchat;
 
 static mut CONFIG: Option<SdkConfig> = None;
 
@@ -22,7 +31,21 @@ static mut CONFIG: Option<SdkConfig> = None;
 async fn main() -> std::io::Result<()> {
     env_logger::init();
 
+    let sender_res = sender::init_sender();
 
+    if sender_res.is_err() {
+        error!("Invalid configuration: {}", sender_res.as_ref().unwrap_err());
+        return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, sender_res.unwrap_err()))
+    }
+
+    let index_res = redis::ensure_tracker_index();
+
+    if index_res.is_err() {
+        error!("Error preparing trackers index: {}", index_res.unwrap_err());
+    }
+
+    actix_web::rt::spawn(scheduler::start_inactivity_scheduler());
+    actix_web::rt::spawn(outbox::start_outbox_dispatcher());
 
     HttpServer::new(|| {
         App::new()
@@ -30,6 +53,22 @@ async fn main() -> std::io::Result<()> {
             .service(incoming)
             .service(outgoing)
             .service(get_tracker_steps)
+            .service(handoff)
+            .service(reply)
+            .service(release)
+            .service(get_transcript)
+            .service(get_summary)
+            .service(classification)
+            .service(quotes)
+            .service(dead_letters)
+            .service(redrive)
+            .service(verify_webhook)
+            .service(webhook)
+            .service(search_trackers)
+            .service(customer_profile)
+            .service(webchat_page)
+            .service(webchat_session)
+            .service(webchat_socket)
     })
     .bind(("0.0.0.0", 8080))?
     .run()
@@ -38,7 +77,12 @@ async fn main() -> std::io::Result<()> {
 
 #[get("/health")]
 async fn health() -> impl Responder {
-    HttpResponse::Ok().body("Hello world!")
+    let upstreams = circuit_breaker::get_breakers_status();
+
+    // Service is still up with an open breaker, only calls to that upstream fail
+    let status = if upstreams.iter().all(|upstream| upstream.state == "closed") { "ok" } else { "degraded" };
+
+    HttpResponse::Ok().body(serde_json::json!({ "status": status, "upstreams": upstreams }).to_string())
 }
 
 #[post("/incoming")]
@@ -55,7 +99,8 @@ async fn incoming(message_log: web::Json<MessageLog>) -> impl Responder {
 
 #[post("/outgoing")]
 async fn outgoing(message_log: web::Json<MessageLog>) -> impl Responder {
-    let response = request_handler::outgoing_message(message_log.0).await;
+    // whatsapp-manager doesn't send the contact name, it's recorded once the user messages are handled
+    let response = request_handler::outgoing_message(message_log.0, None).await;
 
     match response {
         Ok(response) => HttpResponse::Ok().body(serde_json::to_string(&response).unwrap()),
@@ -66,8 +111,114 @@ async fn outgoing(message_log: web::Json<MessageLog>) -> impl Responder {
 }
 
 #[get("/tracker-steps")]
-async fn get_tracker_steps(tracker_id: Query<TrackerParam>) -> impl Responder {
-    let response = request_handler::get_tracker_steps(&tracker_id.tracker_id);
+async fn get_tracker_steps(tracker_id: TrackerId, params: Query<TrackerParam>) -> impl Responder {
+    let response = request_handler::get_tracker_steps(&tracker_id.0, &params);
+
+    match response {
+        Ok(response) => HttpResponse::Ok().body(serde_json::to_string(&response).unwrap()),
+        Err(response) if response.not_found => HttpResponse::NotFound().body(serde_json::to_string(&response).unwrap()),
+        Err(response) => {
+            HttpResponse::InternalServerError().body(serde_json::to_string(&response).unwrap())
+        }
+    }
+}
+
+#[post("/trackers/{tracker_id}/handoff")]
+async fn handoff(tracker_id: TrackerId) -> impl Responder {
+    let response = request_handler::request_handoff(&tracker_id.0);
+
+    match response {
+        Ok(response) => HttpResponse::Ok().body(serde_json::to_string(&response).unwrap()),
+        Err(response) if response.not_found => HttpResponse::NotFound().body(serde_json::to_string(&response).unwrap()),
+        Err(response) => {
+            HttpResponse::InternalServerError().body(serde_json::to_string(&response).unwrap())
+        }
+    }
+}
+
+#[post("/trackers/{tracker_id}/reply")]
+async fn reply(tracker_id: TrackerId, agent_reply: web::Json<AgentReply>) -> impl Responder {
+    let response = request_handler::agent_reply(&tracker_id.0, &agent_reply);
+
+    match response {
+        Ok(response) => HttpResponse::Ok().body(serde_json::to_string(&response).unwrap()),
+        Err(response) if response.not_found => HttpResponse::NotFound().body(serde_json::to_string(&response).unwrap()),
+        Err(response) => {
+            HttpResponse::InternalServerError().body(serde_json::to_string(&response).unwrap())
+        }
+    }
+}
+
+#[post("/trackers/{tracker_id}/release")]
+async fn release(tracker_id: TrackerId, handoff_release: web::Json<HandoffRelease>) -> impl Responder {
+    let response = request_handler::release_handoff(&tracker_id.0, &handoff_release).await;
+
+    match response {
+        Ok(response) => HttpResponse::Ok().body(serde_json::to_string(&response).unwrap()),
+        Err(response) if response.not_found => HttpResponse::NotFound().body(serde_json::to_string(&response).unwrap()),
+        Err(response) => {
+            HttpResponse::InternalServerError().body(serde_json::to_string(&response).unwrap())
+        }
+    }
+}
+
+#[get("/trackers/{tracker_id}/transcript")]
+async fn get_transcript(tracker_id: TrackerId) -> impl Responder {
+    let response = request_handler::get_tracker_transcript(&tracker_id.0);
+
+    match response {
+        Ok(response) => HttpResponse::Ok().body(serde_json::to_string(&response).unwrap()),
+        Err(response) if response.not_found => HttpResponse::NotFound().body(serde_json::to_string(&response).unwrap()),
+        Err(response) => {
+            HttpResponse::InternalServerError().body(serde_json::to_string(&response).unwrap())
+        }
+    }
+}
+
+#[get("/trackers/{tracker_id}/summary")]
+async fn get_summary(tracker_id: TrackerId) -> impl Responder {
+    let response = request_handler::get_tracker_summary(&tracker_id.0);
+
+    match response {
+        Ok(response) => HttpResponse::Ok().body(serde_json::to_string(&response).unwrap()),
+        Err(response) if response.not_found => HttpResponse::NotFound().body(serde_json::to_string(&response).unwrap()),
+        Err(response) => {
+            HttpResponse::InternalServerError().body(serde_json::to_string(&response).unwrap())
+        }
+    }
+}
+
+#[post("/trackers/{tracker_id}/classification")]
+async fn classification(tracker_id: TrackerId, payload: web::Json<PartClassification>) -> impl Responder {
+    let response = request_handler::classify_tracker(&tracker_id.0, &payload).await;
+
+    match response {
+        Ok(response) => HttpResponse::Ok().body(serde_json::to_string(&response).unwrap()),
+        Err(response) if response.not_found => HttpResponse::NotFound().body(serde_json::to_string(&response).unwrap()),
+        Err(response) if response.conflict => HttpResponse::Conflict().body(serde_json::to_string(&response).unwrap()),
+        Err(response) => {
+            HttpResponse::InternalServerError().body(serde_json::to_string(&response).unwrap())
+        }
+    }
+}
+
+#[post("/trackers/{tracker_id}/quotes")]
+async fn quotes(tracker_id: TrackerId, quotes_request: web::Json<QuotesRequest>) -> impl Responder {
+    let response = request_handler::add_quotes(&tracker_id.0, &quotes_request).await;
+
+    match response {
+        Ok(response) => HttpResponse::Ok().body(serde_json::to_string(&response).unwrap()),
+        Err(response) if response.not_found => HttpResponse::NotFound().body(serde_json::to_string(&response).unwrap()),
+        Err(response) if response.conflict => HttpResponse::Conflict().body(serde_json::to_string(&response).unwrap()),
+        Err(response) => {
+            HttpResponse::InternalServerError().body(serde_json::to_string(&response).unwrap())
+        }
+    }
+}
+
+#[get("/outbox/dead-letters")]
+async fn dead_letters(params: Query<OutboxParams>) -> impl Responder {
+    let response = request_handler::get_dead_letters(&params);
 
     match response {
         Ok(response) => HttpResponse::Ok().body(serde_json::to_string(&response).unwrap()),
@@ -76,3 +227,143 @@ async fn get_tracker_steps(tracker_id: Query<TrackerParam>) -> impl Responder {
         }
     }
 }
+
+#[post("/outbox/dead-letters/{entry_id}/redrive")]
+async fn redrive(entry_id: web::Path<String>) -> impl Responder {
+    let response = request_handler::redrive_dead_letter(&entry_id);
+
+    match response {
+        Ok(response) => HttpResponse::Ok().body(serde_json::to_string(&response).unwrap()),
+        Err(response) if response.not_found => HttpResponse::NotFound().body(serde_json::to_string(&response).unwrap()),
+        Err(response) => {
+            HttpResponse::InternalServerError().body(serde_json::to_string(&response).unwrap())
+        }
+    }
+}
+
+#[get("/webhook")]
+async fn verify_webhook(params: Query<HashMap<String, String>>) -> impl Responder {
+    let verify_token = std::env::var("WEBHOOK_VERIFY_TOKEN").unwrap_or_default();
+
+    let subscribed = params.get("hub.mode").map(|mode| mode == "subscribe").unwrap_or(false);
+    let valid_token = !verify_token.is_empty() && params.get("hub.verify_token") == Some(&verify_token);
+
+    if !subscribed || !valid_token {
+        error!("Webhook verification failed");
+        return HttpResponse::Forbidden().finish()
+    }
+
+    HttpResponse::Ok().body(params.get("hub.challenge").cloned().unwrap_or_default())
+}
+
+#[post("/webhook")]
+async fn webhook(request: HttpRequest, body: web::Bytes) -> impl Responder {
+    let signature = request.headers().get("X-Hub-Signature-256").and_then(|signature| signature.to_str().ok()).unwrap_or_default();
+    let app_secret = std::env::var("META_APP_SECRET").unwrap_or_default();
+
+    if app_secret.is_empty() {
+        error!("META_APP_SECRET is not set, webhook events can't be verified");
+        return HttpResponse::InternalServerError().finish()
+    }
+
+    if !tools::verify_signature(&body, signature, &app_secret) {
+        error!("Invalid webhook signature");
+        return HttpResponse::Unauthorized().finish()
+    }
+
+    let raw_event: Result<serde_json::Value, serde_json::Error> = serde_json::from_slice(&body);
+    let event: Result<Event, serde_json::Error> = serde_json::from_slice(&body);
+
+    if raw_event.is_err() || event.is_err() {
+        error!("Invalid webhook event");
+        return HttpResponse::BadRequest().finish()
+    }
+
+    let response = request_handler::webhook_event(&event.unwrap(), &raw_event.unwrap()).await;
+
+    // Meta retries events not answered with 200, flow errors are only logged to avoid processing them twice
+    match response {
+        Ok(response) => HttpResponse::Ok().body(serde_json::to_string(&response).unwrap()),
+        Err(response) => {
+            error!("Error processing webhook event: {:?}", response.errors);
+            HttpResponse::Ok().body(serde_json::to_string(&response).unwrap())
+        }
+    }
+}
+
+#[get("/trackers")]
+async fn search_trackers(params: Query<TrackerSearchParams>) -> impl Responder {
+    let response = request_handler::search_trackers(&params);
+
+    match response {
+        Ok(response) => HttpResponse::Ok().body(serde_json::to_string(&response).unwrap()),
+        Err(response) => {
+            HttpResponse::InternalServerError().body(serde_json::to_string(&response).unwrap())
+        }
+    }
+}
+
+#[get("/customers/{phone_number}")]
+async fn customer_profile(phone_number: web::Path<String>) -> impl Responder {
+    let response = request_handler::get_customer_profile(&phone_number);
+
+    match response {
+        Ok(response) => HttpResponse::Ok().body(serde_json::to_string(&response).unwrap()),
+        Err(response) if response.not_found => HttpResponse::NotFound().body(serde_json::to_string(&response).unwrap()),
+        Err(response) => {
+            HttpResponse::InternalServerError().body(serde_json::to_string(&response).unwrap())
+        }
+    }
+}
+
+#[get("/webchat")]
+async fn webchat_page() -> impl Responder {
+    HttpResponse::Ok().content_type("text/html; charset=utf-8").body(include_str!("webchat.html"))
+}
+
+#[post("/webchat/session")]
+async fn webchat_session() -> impl Responder {
+    match webchat::issue_session() {
+        Ok(session_id) => HttpResponse::Ok().json(serde_json::json!({ "session_id": session_id })),
+        Err(err) => {
+            error!("Error issuing web chat session: {}", err);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+#[get("/webchat/ws")]
+async fn webchat_socket(request: HttpRequest, body: web::Payload, params: Query<HashMap<String, String>>) -> Result<HttpResponse, actix_web::Error> {
+    let session_id = params.get("session").cloned().unwrap_or_default();
+    let (response, session, stream) = actix_ws::handle(&request, body)?;
+
+    // Browsers can't read the handshake status, rejected sessions are closed with a code instead
+    if !webchat::is_valid_session(&session_id) {
+        webchat::reject_session(session).await;
+        return Ok(response)
+    }
+
+    actix_web::rt::spawn(webchat::run_session(session_id, session, stream));
+
+    Ok(response)
+}
+
+// Tracker id of the path or the query, resolved once for every tracker API since they accept the ticket number as well
+struct TrackerId(String);
+
+impl FromRequest for TrackerId {
+    type Error = actix_web::Error;
+    type Future = Ready<Result<TrackerId, actix_web::Error>>;
+
+    fn from_request(request: &HttpRequest, _: &mut Payload) -> Self::Future {
+        let tracker_id = request.match_info().get("tracker_id").map(|tracker_id| tracker_id.to_string())
+            .or_else(|| Query::<HashMap<String, String>>::from_query(request.query_string()).ok()
+                .and_then(|params| params.get("tracker_id").cloned()))
+            .unwrap_or_default();
+
+        ready(request_handler::resolve_tracker_id(&tracker_id).map(TrackerId).map_err(|response| {
+            let mut error_response = if response.not_found { HttpResponse::NotFound() } else { HttpResponse::InternalServerError() };
+            InternalError::from_response("Tracker not resolved", error_response.body(serde_json::to_string(&response).unwrap())).into()
+        }))
+    }
+}
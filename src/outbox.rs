@@ -0,0 +1,299 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use crate::redis::{claim_outbox_entry, complete_outbox_entry, dead_letter_outbox_entry, get_delivery_entry, get_due_outbox_entries, get_outbox_entry, mark_delivery_redelivered, publish_agent_notification, publish_message, save_message_delivery, schedule_outbox_entry};
+use crate::channel::get_channel;
+use crate::constants::SYSTEM_ID;
+use crate::request_handler::outgoing_message;
+use fizzy_commons::shared_structs::MessageRequest;
+use crate::structs::{AgentNotification, MessageDelivery, MessageTemplate, OutboxEntry, WorkflowEvent};
+use crate::tools::{get_env_setting, send_message_with_template};
+
+// Entries handled on every dispatch
+const DISPATCH_BATCH_SIZE: usize = 50;
+
+// Time an entry is held by a dispatcher before it can be claimed again
+const CLAIM_LEASE_MILLIS: u64 = 60000;
+
+// Periodically delivers the messages queued in the outbox
+pub async fn start_outbox_dispatcher() {
+    let dispatch_interval = get_env_setting("OUTBOX_DISPATCH_MILLIS", 500);
+    let mut interval = actix_web::rt::time::interval(Duration::from_millis(dispatch_interval));
+
+    info!("Starting outbox dispatcher, dispatching every {} milliseconds", dispatch_interval);
+    loop {
+        interval.tick().await;
+
+        let res = dispatch_outbox().await;
+
+        if res.is_err() {
+            error!("Error dispatching outbox: {}", res.unwrap_err());
+        }
+    }
+}
+
+pub async fn dispatch_outbox() -> Result<(), String> {
+    let now = get_timestamp();
+
+    let due_entries = get_due_outbox_entries(now, DISPATCH_BATCH_SIZE);
+
+    if due_entries.is_err() {
+        return Err(due_entries.unwrap_err())
+    }
+
+    for entry_id in due_entries.unwrap() {
+        let claimed = claim_outbox_entry(&entry_id, now, now + CLAIM_LEASE_MILLIS);
+
+        if claimed.is_err() {
+            error!("Error claiming outbox entry {}: {}", entry_id, claimed.unwrap_err());
+            continue
+        }
+
+        if !claimed.unwrap() {
+            debug!("Outbox entry {} taken by another dispatcher", entry_id);
+            continue
+        }
+
+        let entry = match get_outbox_entry(&entry_id) {
+            Ok(entry) => entry,
+            Err(err) => {
+                error!("Error obtaining outbox entry {}: {}", entry_id, err);
+                continue
+            }
+        };
+
+        let res = deliver_entry(entry).await;
+
+        if res.is_err() {
+            error!("Error delivering outbox entry {}: {}", entry_id, res.unwrap_err());
+        }
+    }
+
+    Ok(())
+}
+
+async fn deliver_entry(mut entry: OutboxEntry) -> Result<(), String> {
+    info!("Delivering outbox entry {} for tracker {}", entry.id, entry.tracker_id);
+
+    // Each recipient is sent apart, so its reference is known and only the failed ones are retried
+    let mut sent: Vec<(String, String)> = vec![];
+    let mut failed_recipients: Vec<String> = vec![];
+    let mut errors: Vec<String> = vec![];
+
+    for phone_number in entry.message.to.clone() {
+        let res = send_message_with_template(&for_recipient(&entry.message, &phone_number), entry.template.as_ref()).await;
+
+        if res.is_err() {
+            errors.push(format!("Error sending message to {}: {}", phone_number, res.unwrap_err()));
+            failed_recipients.push(phone_number);
+            continue
+        }
+
+        let reference = res.unwrap().references.get(0).map(|reference| reference.reference.clone()).unwrap_or_default();
+        sent.push((reference, phone_number));
+    }
+
+    if sent.is_empty() {
+        return retry_entry(entry, errors.join(", "))
+    }
+
+    // Let the flow know the message was sent, so it can continue with the next step
+    if entry.notification.is_some() {
+        let mut notification = entry.notification.take().unwrap();
+        notification.register_id = sent[0].0.clone();
+
+        let event = WorkflowEvent::new("StepCreated", &entry.tracker_id, &entry.step_id);
+        let publish_res = publish_message(&notification, &notification.phone_number, &event);
+
+        if publish_res.is_err() {
+            error!("Error publishing message {}", publish_res.unwrap_err());
+        }
+
+        // Without whatsapp-manager routing the notification, this system continues the flow itself
+        if get_channel(&notification.phone_number).continues_flow() && notification.destination_systems.contains(&SYSTEM_ID.to_string()) {
+            actix_web::rt::spawn(async move {
+                let res = outgoing_message(notification, None).await;
+
+                if res.is_err() {
+                    error!("Error continuing flow: {:?}", res.unwrap_err().errors);
+                }
+            });
+        }
+    }
+
+    // References are kept to follow the delivery statuses of each recipient
+    let now = get_timestamp();
+    for (reference, phone_number) in &sent {
+        if reference.is_empty() {
+            continue
+        }
+
+        let save_res = save_message_delivery(reference, &entry, phone_number, now);
+
+        if save_res.is_err() {
+            error!("Error saving message delivery {}", save_res.unwrap_err());
+        }
+    }
+
+    if !failed_recipients.is_empty() {
+        entry.message.to = failed_recipients;
+        return retry_entry(entry, errors.join(", "))
+    }
+
+    complete_outbox_entry(&entry.id)
+}
+
+// Copy of the message addressed to a single recipient
+fn for_recipient(message: &MessageRequest, phone_number: &str) -> MessageRequest {
+    let mut recipient_message: MessageRequest = serde_json::from_value(serde_json::to_value(message).unwrap()).unwrap();
+    recipient_message.to = vec![phone_number.to_string()];
+
+    recipient_message
+}
+
+// Queues a message that isn't created along with a step, it's delivered like the step messages
+pub fn queue_message(tracker_id: &str, message: MessageRequest, template: Option<MessageTemplate>) -> Result<String, String> {
+    let now = get_timestamp();
+
+    let entry = OutboxEntry{
+        id: uuid::Uuid::new_v4().to_string().replace("-", ""),
+        tracker_id: tracker_id.to_string(),
+        step_id: "".to_string(),
+        message,
+        notification: None,
+        attempts: 0,
+        created: now,
+        last_error: None,
+        delivery_retries: 0,
+        template,
+    };
+
+    let res = schedule_outbox_entry(&entry, now);
+
+    if res.is_err() {
+        return Err(res.unwrap_err())
+    }
+
+    Ok(entry.id)
+}
+
+// Queues the message again when WhatsApp reports it failed, agents are alerted once the retries are exhausted
+pub fn redeliver_failed_message(delivery: &MessageDelivery) -> Result<(), String> {
+    let max_retries = get_env_setting("DELIVERY_MAX_RETRIES", 1) as u32;
+
+    // WhatsApp may report the failure more than once, only the first report is handled
+    let first_report = mark_delivery_redelivered(&delivery.reference, get_timestamp());
+
+    if first_report.is_err() {
+        return Err(first_report.unwrap_err())
+    }
+
+    if !first_report.unwrap() {
+        info!("Failure of message {} was already handled", delivery.reference);
+        return Ok(())
+    }
+
+    let mut entry = match get_delivery_entry(&delivery.reference) {
+        Ok(entry) => entry,
+        Err(err) => return Err(err),
+    };
+
+    if entry.delivery_retries >= max_retries {
+        error!("Message {} for tracker {} failed after {} retries, alerting agents", delivery.reference, delivery.tracker_id, entry.delivery_retries);
+
+        let notification = AgentNotification{
+            tracker_id: delivery.tracker_id.clone(),
+            phone_number: delivery.phone_number.clone(),
+            timestamp: get_timestamp().to_string(),
+            event: "DELIVERY_FAILED".to_string(),
+            content: delivery.error.clone().unwrap_or_default(),
+        };
+
+        let publish_res = publish_agent_notification(&notification);
+
+        if publish_res.is_err() {
+            return Err(format!("Error publishing agent notification {}", publish_res.unwrap_err()))
+        }
+
+        return Ok(())
+    }
+
+    // Notification was already published when the message was first sent
+    entry.notification = None;
+    entry.attempts = 0;
+    entry.delivery_retries += 1;
+    entry.last_error = delivery.error.clone();
+
+    info!("Message {} for tracker {} failed, queueing it again", delivery.reference, delivery.tracker_id);
+    schedule_outbox_entry(&entry, get_timestamp())
+}
+
+fn retry_entry(mut entry: OutboxEntry, error: String) -> Result<(), String> {
+    let max_attempts = get_env_setting("OUTBOX_MAX_ATTEMPTS", 5) as u32;
+    let now = get_timestamp();
+
+    entry.attempts += 1;
+    entry.last_error = Some(error);
+
+    if entry.attempts >= max_attempts {
+        error!("Outbox entry {} failed {} times, moving it to dead letters", entry.id, entry.attempts);
+        let res = dead_letter_outbox_entry(&entry, now);
+
+        if res.is_err() {
+            return res
+        }
+
+        // Customer is left without the message until it's re-driven, so agents are alerted
+        let notification = AgentNotification{
+            tracker_id: entry.tracker_id.clone(),
+            phone_number: entry.message.to.join(","),
+            timestamp: now.to_string(),
+            event: "OUTBOX_DEAD_LETTER".to_string(),
+            content: entry.last_error.clone().unwrap_or_default(),
+        };
+
+        let publish_res = publish_agent_notification(&notification);
+
+        if publish_res.is_err() {
+            return Err(format!("Error publishing agent notification {}", publish_res.unwrap_err()))
+        }
+
+        return Ok(())
+    }
+
+    let delay = backoff_delay(entry.attempts, get_env_setting("OUTBOX_BACKOFF_MILLIS", 2000), get_env_setting("OUTBOX_MAX_BACKOFF_MILLIS", 300000));
+    info!("Retrying outbox entry {} in {} milliseconds", entry.id, delay);
+
+    schedule_outbox_entry(&entry, now + delay)
+}
+
+// Delay doubles on every failed attempt, up to the max delay
+fn backoff_delay(attempts: u32, base_delay: u64, max_delay: u64) -> u64 {
+    let exponent = attempts.saturating_sub(1).min(32);
+
+    base_delay.saturating_mul(2u64.saturating_pow(exponent)).min(max_delay)
+}
+
+// Messages are received through the webhook instead of whatsapp-manager
+pub fn is_standalone() -> bool {
+    std::env::var("STANDALONE_MODE").map(|value| value == "true").unwrap_or(false)
+}
+
+fn get_timestamp() -> u64 {
+    match SystemTime::now().duration_since(UNIX_EPOCH) {
+        Ok(n) => n.as_millis() as u64,
+        Err(_) => panic!("SystemTime before UNIX EPOCH!"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::outbox::backoff_delay;
+
+    #[test]
+    fn backoff_doubles_until_max_delay() {
+        assert_eq!(backoff_delay(1, 2000, 300000), 2000);
+        assert_eq!(backoff_delay(2, 2000, 300000), 4000);
+        assert_eq!(backoff_delay(4, 2000, 300000), 16000);
+        assert_eq!(backoff_delay(10, 2000, 300000), 300000);
+        assert_eq!(backoff_delay(100, 2000, 300000), 300000);
+    }
+}
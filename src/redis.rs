@@ -1,9 +1,57 @@
 use std::collections::HashMap;
 use std::error::Error;
-use crate::structs::{Event, MessageLog, RequestTracker, TrackerStep};
+use fizzy_commons::shared_structs::MessageRequest;
+use crate::structs::{AgentNotification, CustomerProfile, Event, InboundMessage, MessageDelivery, OutboxEntry, OutgoingMessage, RequestTracker, SavedVehicle, SupplierQuote, TrackerStep, WorkflowEvent};
 use redis::{Client, Commands, JsonCommands, RedisError, RedisResult, Value};
+use redis::streams::StreamMaxlen;
 use std::time::{SystemTime, UNIX_EPOCH};
+use serde::Serialize;
 use redis::Value::Bulk;
+use crate::constants::{DEFAULT_LANGUAGE, FlowStatus, TICKET_PREFIX, TrackerState};
+use crate::tools::{format_ticket_number, get_env_setting};
+
+// Sorted set of active trackers scored by their last step timestamp
+const ACTIVE_TRACKERS_KEY: &str = "active-trackers";
+
+// Stream where published messages are appended, trimmed to approximately the max length
+const WORKFLOW_EVENTS_STREAM: &str = "workflow-events";
+const WORKFLOW_EVENTS_MAXLEN: usize = 100000;
+
+// Outbox entries by id, entries due for delivery scored by next attempt and entries that exhausted their attempts
+const OUTBOX_ENTRIES_KEY: &str = "outbox-entries";
+const OUTBOX_PENDING_KEY: &str = "outbox-pending";
+const OUTBOX_DEAD_LETTER_KEY: &str = "outbox-dead-letter";
+
+// Outgoing messages kept per recipient, older ones are trimmed when a new one is saved
+const MAX_OUTGOING_MESSAGES: isize = 500;
+
+// Meta retries undelivered webhook events for up to 7 days
+const PROCESSED_MESSAGE_TTL_SECONDS: usize = 604800;
+
+// Delivery records are kept for 30 days, statuses are rarely reported after that
+const MESSAGE_DELIVERY_TTL_SECONDS: usize = 2592000;
+const DELIVERY_STATUSES: [&str; 4] = ["sent", "delivered", "read", "failed"];
+
+// Web chat sessions expire after 30 days without the page connecting
+const WEBCHAT_SESSION_TTL_SECONDS: usize = 2592000;
+
+const TRACKERS_INDEX: &str = "userTrackers";
+
+// Steps obtained per query when retrieving all the tracker steps
+const STEPS_PAGE_SIZE: usize = 100;
+
+// Counter the tracker ticket numbers are taken from
+const TICKET_COUNTER_KEY: &str = "tracker-ticket-counter";
+
+// Tracker fields added to the index for searches, along with their type
+const TRACKER_SEARCH_FIELDS: [(&str, &str); 6] = [
+    ("status", "TAG"),
+    ("state", "TAG"),
+    ("brand", "TAG"),
+    ("model", "TAG"),
+    ("updated", "NUMERIC SORTABLE"),
+    ("ticket_number", "TAG"),
+];
 
 pub fn get_user_mode(phone_number: &str) -> Result<u16, RedisError> {
     let client = create_client().unwrap();
@@ -20,6 +68,21 @@ pub fn get_user_mode(phone_number: &str) -> Result<u16, RedisError> {
     Ok(parsed_mode)
 }
 
+// Stores the webhook event where whatsapp-manager would have left it
+pub fn save_user_message(message_id: &str, phone_number: &str, event: &serde_json::Value) -> Result<(), String> {
+    let client = create_client().unwrap();
+    let mut con = client.get_connection().unwrap();
+
+    let res: RedisResult<bool> = con.json_set(format!("incoming-messages:{}:{}", phone_number, message_id), ".", event);
+
+    if res.is_err() {
+        error!("Error saving user message: {}", res.as_ref().unwrap_err());
+        return Err(format!("Error saving user message: {}", res.as_ref().unwrap_err()))
+    }
+
+    Ok(())
+}
+
 fn create_client() -> Result<Client, RedisError> {
     let url = std::env::var("REDIS_URL").unwrap();
     let client = redis::Client::open(url);
@@ -52,9 +115,96 @@ pub fn get_user_message(message_id: &str, phone_number: &str) -> Result<Event, R
         return Err(res.unwrap_err());
     }
 
-    let event: Event = serde_json::from_str(&res.unwrap()).unwrap();
+    // Messages from other channels are stored in a different format
+    let event: Result<Event, serde_json::Error> = serde_json::from_str(&res.unwrap());
+
+    if event.is_err() {
+        return Err(RedisError::from((redis::ErrorKind::TypeError, "Stored message isn't a WhatsApp event")))
+    }
+
+    Ok(event.unwrap())
+}
+
+// Messages received through channels other than WhatsApp, stored already normalized
+pub fn save_inbound_message(message: &InboundMessage) -> Result<(), String> {
+    let client = create_client().unwrap();
+    let mut con = client.get_connection().unwrap();
+
+    let res: RedisResult<bool> = con.json_set(format!("incoming-messages:{}:{}", message.user_id, message.id), ".", message);
+
+    if res.is_err() {
+        error!("Error saving inbound message: {}", res.as_ref().unwrap_err());
+        return Err(format!("Error saving inbound message: {}", res.as_ref().unwrap_err()))
+    }
+
+    Ok(())
+}
+
+pub fn get_inbound_message(user_id: &str, message_id: &str) -> Result<InboundMessage, String> {
+    let client = create_client().unwrap();
+    let mut con = client.get_connection().unwrap();
+
+    let res: RedisResult<String> = con.json_get(format!("incoming-messages:{}:{}", user_id, message_id), ".");
+
+    if res.is_err() {
+        return Err(format!("Error getting inbound message: {}", res.unwrap_err()))
+    }
+
+    serde_json::from_str(&res.unwrap()).map_err(|err| format!("Invalid inbound message: {}", err))
+}
+
+// Ticket number of a tracker created before they were introduced, a number already assigned is kept.
+// The counter is only increased when the tracker has no number, so the sequence has no gaps
+pub fn assign_ticket_number(tracker_id: &str) -> Result<String, String> {
+    let client = create_client().unwrap();
+    let mut con = client.get_connection().unwrap();
+
+    // Formatted as in format_ticket_number
+    let script = redis::Script::new(r"
+        local ticket_number = redis.call('HGET', KEYS[1], 'ticket_number')
+        if ticket_number and ticket_number ~= '' then
+            return ticket_number
+        end
+        ticket_number = string.format('%s%06d', ARGV[1], redis.call('INCR', KEYS[2]))
+        redis.call('HSET', KEYS[1], 'ticket_number', ticket_number)
+        return ticket_number
+    ");
+
+    let res: RedisResult<String> = script.key(format!("whatsapp-request:{}", tracker_id)).key(TICKET_COUNTER_KEY).arg(TICKET_PREFIX).invoke(&mut con);
+
+    if res.is_err() {
+        return Err(format!("Error assigning ticket number to tracker {}: {}", tracker_id, res.unwrap_err()))
+    }
+
+    Ok(res.unwrap())
+}
+
+// Web chat session issued to a page
+pub fn create_webchat_session(session_id: &str) -> Result<(), String> {
+    let client = create_client().unwrap();
+    let mut con = client.get_connection().unwrap();
+
+    let res: RedisResult<()> = con.set_ex(format!("webchat-session:{}", session_id), "1", WEBCHAT_SESSION_TTL_SECONDS);
+
+    if res.is_err() {
+        return Err(format!("Error creating web chat session: {}", res.unwrap_err()))
+    }
+
+    Ok(())
+}
+
+// Extends the web chat session, returning false when it wasn't issued or already expired
+pub fn touch_webchat_session(session_id: &str) -> Result<bool, String> {
+    let client = create_client().unwrap();
+    let mut con = client.get_connection().unwrap();
+
+    let res: RedisResult<bool> = con.expire(format!("webchat-session:{}", session_id), WEBCHAT_SESSION_TTL_SECONDS);
+
+    if res.is_err() {
+        return Err(format!("Error extending web chat session: {}", res.unwrap_err()))
+    }
 
-    Ok(event)
+    Ok(res.unwrap())
 }
 
 pub fn create_new_tracker(tracker_id: &str, phone_number: &str) -> Result<String, RedisError> {
@@ -67,19 +217,287 @@ pub fn create_new_tracker(tracker_id: &str, phone_number: &str) -> Result<String
         Err(_) => panic!("SystemTime before UNIX EPOCH!"),
     };
 
+    // Ticket numbers are sequential across all trackers, INCR keeps them unique between instances
+    let ticket: Result<u64, RedisError> = con.incr(TICKET_COUNTER_KEY, 1);
+
+    if ticket.is_err() {
+        return Err(ticket.unwrap_err());
+    }
+
+    let ticket_number = format_ticket_number(ticket.unwrap());
+
     // Create registry
     let res: Result<String, RedisError> = con.hset_multiple(
         format!("whatsapp-request:{}", tracker_id),
-        &[("phone_number", phone_number), ("timestamp", &timestamp)],
+        &[("phone_number", phone_number), ("timestamp", &timestamp), ("state", TrackerState::Active.value()), ("ticket_number", &ticket_number)],
     );
 
     if res.is_err() {
         return Err(res.unwrap_err());
     }
 
+    // Track activity so the tracker can be expired after a period without new steps
+    let res: Result<u16, RedisError> = con.zadd(ACTIVE_TRACKERS_KEY, tracker_id, &timestamp);
+
+    if res.is_err() {
+        return Err(res.unwrap_err());
+    }
+
     return Ok(format!("whatsapp-request:{}", tracker_id));
 }
 
+pub fn set_tracker_field(tracker_id: &str, field: &str, value: &str) -> Result<(), String> {
+    let client = create_client().unwrap();
+    let mut con = client.get_connection().unwrap();
+
+    let res: RedisResult<u16> = con.hset(format!("whatsapp-request:{}", tracker_id), field, value);
+
+    if res.is_err() {
+        error!("Error updating tracker field {}: {}", field, res.as_ref().unwrap_err());
+        return Err(format!("Error updating tracker field {}: {}", field, res.as_ref().unwrap_err()))
+    }
+
+    Ok(())
+}
+
+// Returns active trackers without activity since the specified timestamp, along with their last activity
+pub fn get_inactive_trackers(since: u64) -> Result<Vec<(String, u64)>, String> {
+    let client = create_client().unwrap();
+    let mut con = client.get_connection().unwrap();
+
+    let res: RedisResult<Vec<(String, u64)>> = con.zrangebyscore_withscores(ACTIVE_TRACKERS_KEY, "-inf", since.to_string());
+
+    if res.is_err() {
+        return Err(res.unwrap_err().to_string())
+    }
+
+    Ok(res.unwrap())
+}
+
+// Updates the tracker state, only active trackers are tracked for inactivity
+pub fn set_tracker_state(tracker_id: &str, state: TrackerState) -> Result<(), String> {
+    let client = create_client().unwrap();
+    let mut con = client.get_connection().unwrap();
+
+    let res: RedisResult<u16> = con.hset(format!("whatsapp-request:{}", tracker_id), "state", state.value());
+
+    if res.is_err() {
+        error!("Error updating tracker {} state: {}", tracker_id, res.as_ref().unwrap_err());
+        return Err(format!("Error updating tracker {} state: {}", tracker_id, res.as_ref().unwrap_err()))
+    }
+
+    let res: RedisResult<u16> = if state == TrackerState::Active {
+        let timestamp = match SystemTime::now().duration_since(UNIX_EPOCH) {
+            Ok(n) => n.as_millis().to_string(),
+            Err(_) => panic!("SystemTime before UNIX EPOCH!"),
+        };
+
+        con.zadd(ACTIVE_TRACKERS_KEY, tracker_id, timestamp)
+    } else {
+        con.zrem(ACTIVE_TRACKERS_KEY, tracker_id)
+    };
+
+    if res.is_err() {
+        error!("Error updating tracker {} state: {}", tracker_id, res.as_ref().unwrap_err());
+        return Err(format!("Error updating tracker {} state: {}", tracker_id, res.as_ref().unwrap_err()))
+    }
+
+    Ok(())
+}
+
+pub fn increment_tracker_field(tracker_id: &str, field: &str) -> Result<i64, String> {
+    let client = create_client().unwrap();
+    let mut con = client.get_connection().unwrap();
+
+    let res: RedisResult<i64> = con.hincr(format!("whatsapp-request:{}", tracker_id), field, 1);
+
+    if res.is_err() {
+        error!("Error incrementing tracker field {}: {}", field, res.as_ref().unwrap_err());
+        return Err(format!("Error incrementing tracker field {}: {}", field, res.as_ref().unwrap_err()))
+    }
+
+    Ok(res.unwrap())
+}
+
+pub fn publish_agent_notification(notification: &AgentNotification) -> Result<String, Box<dyn Error>> {
+    let client = create_client()?;
+    let mut con = client.get_connection()?;
+
+    let channel = std::env::var("AGENT_CHANNEL").unwrap_or("agent-notification".to_string());
+    let _: () = con.publish(channel, serde_json::to_string(notification).unwrap())?;
+
+    Ok("OK".to_string())
+}
+
+pub fn save_outgoing_message(message: &MessageRequest, reference: &str) -> Result<(), String> {
+    let client = create_client().unwrap();
+    let mut con = client.get_connection().unwrap();
+
+    let timestamp = match SystemTime::now().duration_since(UNIX_EPOCH) {
+        Ok(n) => n.as_millis() as u64,
+        Err(_) => panic!("SystemTime before UNIX EPOCH!"),
+    };
+
+    // Messages are stored per recipient, scored by the time they were sent
+    for phone_number in &message.to {
+        // Messages are linked to the recipient's tracker at the time they were sent
+        let tracker_id = get_last_tracker(phone_number).map(|tracker| tracker.id).unwrap_or_default();
+
+        let outgoing_message = OutgoingMessage{
+            timestamp,
+            reference: reference.to_string(),
+            tracker_id,
+            message: serde_json::to_value(message).unwrap(),
+        };
+
+        let key = format!("outgoing-messages:{}", phone_number);
+        let res: RedisResult<()> = redis::pipe()
+            .atomic()
+            .zadd(&key, serde_json::to_string(&outgoing_message).unwrap(), timestamp).ignore()
+            .zremrangebyrank(&key, 0, -(MAX_OUTGOING_MESSAGES + 1)).ignore()
+            .query(&mut con);
+
+        if res.is_err() {
+            return Err(res.unwrap_err().to_string())
+        }
+    }
+
+    Ok(())
+}
+
+pub fn get_outgoing_messages(phone_number: &str, from: u64, to: Option<u64>) -> Result<Vec<OutgoingMessage>, String> {
+    let client = create_client().unwrap();
+    let mut con = client.get_connection().unwrap();
+
+    let to = to.map(|to| to.to_string()).unwrap_or("+inf".to_string());
+    let res: RedisResult<Vec<String>> = con.zrangebyscore(format!("outgoing-messages:{}", phone_number), from, to);
+
+    if res.is_err() {
+        return Err(res.unwrap_err().to_string())
+    }
+
+    Ok(res.unwrap().iter().map(|message| serde_json::from_str(message).unwrap()).collect())
+}
+
+// Creates the trackers index or adds the fields used for searches to an existing one
+pub fn ensure_tracker_index() -> Result<(), String> {
+    let client = create_client().unwrap();
+    let mut con = client.get_connection().unwrap();
+
+    let info: RedisResult<Value> = redis::cmd("FT.INFO").arg(TRACKERS_INDEX).query(&mut con);
+
+    if info.is_err() {
+        info!("Creating index {}", TRACKERS_INDEX);
+        let mut schema: Vec<&str> = vec!["phone_number", "TEXT", "timestamp", "NUMERIC", "SORTABLE"];
+        for (field, field_type) in TRACKER_SEARCH_FIELDS {
+            schema.push(field);
+            schema.extend(field_type.split(" "));
+        }
+
+        let res: RedisResult<String> = redis::cmd("FT.CREATE")
+            .arg(TRACKERS_INDEX)
+            .arg("ON").arg("HASH")
+            .arg("PREFIX").arg("1").arg("whatsapp-request:")
+            .arg("SCHEMA")
+            .arg(schema)
+            .query(&mut con);
+
+        if res.is_err() {
+            return Err(res.unwrap_err().to_string())
+        }
+
+        return Ok(())
+    }
+
+    for (field, field_type) in TRACKER_SEARCH_FIELDS {
+        let res: RedisResult<String> = redis::cmd("FT.ALTER")
+            .arg(TRACKERS_INDEX)
+            .arg("SCHEMA").arg("ADD")
+            .arg(field)
+            .arg(field_type.split(" ").collect::<Vec<&str>>())
+            .query(&mut con);
+
+        // Fields already in the index are skipped
+        if res.is_err() && !res.as_ref().unwrap_err().to_string().contains("Duplicate") {
+            return Err(res.unwrap_err().to_string())
+        }
+    }
+
+    Ok(())
+}
+
+// Searches trackers with a RediSearch query, returns the total of matches and the requested page
+pub fn search_trackers(query: &str, sort_by: &str, ascending: bool, offset: usize, limit: usize) -> Result<(u64, Vec<RequestTracker>), String> {
+    let client = create_client().unwrap();
+    let mut con = client.get_connection().unwrap();
+
+    let res: RedisResult<Value> = redis::cmd("FT.SEARCH")
+        .arg(TRACKERS_INDEX)
+        .arg(query)
+        .arg("SORTBY")
+        .arg(sort_by)
+        .arg(if ascending { "ASC" } else { "DESC" })
+        .arg("LIMIT")
+        .arg(offset)
+        .arg(limit)
+        .query(&mut con);
+
+    if res.is_err() {
+        return Err(res.unwrap_err().to_string())
+    }
+
+    let results = parse_search_results(&res.unwrap());
+
+    if results.is_err() {
+        return Err(results.unwrap_err())
+    }
+
+    let (total, registers) = results.unwrap();
+    let trackers = registers.iter()
+        .map(|(key, params)| parse_tracker(&key.replace("whatsapp-request:", ""), params))
+        .collect();
+
+    Ok((total, trackers))
+}
+
+// Parses FT.SEARCH response into the total of matches and each found key along with its fields
+fn parse_search_results(value: &Value) -> Result<(u64, Vec<(String, HashMap<String, String>)>), String> {
+    let mut registers: Vec<(String, HashMap<String, String>)> = vec![];
+
+    let items = match value {
+        Bulk(items) => items,
+        _ => return Err(format!("Unexpected search response {:?}", value)),
+    };
+
+    let total = match items.get(0) {
+        Some(Value::Int(total)) => *total as u64,
+        _ => return Err("Search response doesn't start with the total of matches".to_string()),
+    };
+
+    // Results are sent as key followed by its fields
+    for register in items[1..].chunks(2) {
+        if register.len() != 2 {
+            return Err("Search response has a key without fields".to_string())
+        }
+
+        let key: RedisResult<String> = redis::from_redis_value(&register[0]);
+        let fields: RedisResult<Vec<String>> = redis::from_redis_value(&register[1]);
+
+        if key.is_err() || fields.is_err() {
+            return Err("Search response has a malformed result".to_string())
+        }
+
+        let mut params: HashMap<String, String> = HashMap::new();
+        for field in fields.unwrap().chunks(2) {
+            params.insert(field[0].clone(), field.get(1).cloned().unwrap_or_default());
+        }
+
+        registers.push((key.unwrap(), params));
+    }
+
+    Ok((total, registers))
+}
+
 pub fn is_nil(error: &RedisError) -> bool {
     error.to_string().contains("response was nil")
 }
@@ -111,11 +529,6 @@ pub fn get_last_tracker(phone_number: &str) -> Result<RequestTracker, String>{
         }
     }
     let register = res.unwrap();
-   let mut requestTracker: RequestTracker = RequestTracker{
-       phone_number: "".to_string(),
-       timestamp: "".to_string(),
-       id: String::from(&register[0].1.replace("whatsapp-request:", "")),
-   };
 
     let mut params: HashMap<String, String> = HashMap::new();
     let mut param_name = "";
@@ -128,10 +541,45 @@ pub fn get_last_tracker(phone_number: &str) -> Result<RequestTracker, String>{
         }
     }
 
-    requestTracker.timestamp = params.get("timestamp").expect("timestamp param couldnt be found").clone();
-    requestTracker.phone_number = params.get("phone_number").expect("phone_number param couldnt be found").clone();
+    Ok(parse_tracker(&register[0].1.replace("whatsapp-request:", ""), &params))
+}
+
+pub fn get_tracker(tracker_id: &str) -> Result<RequestTracker, String>{
+    let client = create_client().unwrap();
+    let mut con = client.get_connection().unwrap();
+
+    let res: RedisResult<HashMap<String, String>> = con.hgetall(format!("whatsapp-request:{}", tracker_id));
+
+    if res.is_err() {
+        return Err(res.unwrap_err().to_string())
+    }
+
+    let params = res.unwrap();
+
+    if params.is_empty() {
+        return Err("No records found".to_string())
+    }
+
+    Ok(parse_tracker(tracker_id, &params))
+}
 
-    Ok(requestTracker)
+fn parse_tracker(tracker_id: &str, params: &HashMap<String, String>) -> RequestTracker {
+    RequestTracker{
+        phone_number: params.get("phone_number").expect("phone_number param couldnt be found").clone(),
+        timestamp: params.get("timestamp").expect("timestamp param couldnt be found").clone(),
+        id: tracker_id.to_string(),
+
+        // Optional params, not present on trackers created before they were introduced
+        edit_return_status: params.get("edit_return_status").cloned().unwrap_or_default(),
+        state: params.get("state").cloned().unwrap_or(TrackerState::Active.value().to_string()),
+        reminder_sent: params.get("reminder_sent").map(|sent| sent == "1").unwrap_or(false),
+        resume_prompt_sent: params.get("resume_prompt_sent").map(|sent| sent == "1").unwrap_or(false),
+        status: params.get("status").cloned().unwrap_or_default(),
+        updated: params.get("updated").cloned().unwrap_or_default(),
+        brand: params.get("brand").cloned().unwrap_or_default(),
+        model: params.get("model").cloned().unwrap_or_default(),
+        ticket_number: params.get("ticket_number").cloned().unwrap_or_default(),
+    }
 }
 
 
@@ -170,6 +618,7 @@ pub fn get_last_tracker_step(tracker_id: &str) -> Result<TrackerStep, String>{
         value: "".to_string(),
         attached_files: "".to_string(),
         message_reference: "".to_string(),
+        origin: "".to_string(),
     };
 
     let mut params: HashMap<String, String> = HashMap::new();
@@ -192,6 +641,7 @@ pub fn get_last_tracker_step(tracker_id: &str) -> Result<TrackerStep, String>{
     trackerStep.value = params.get("value").expect("value param couldnt be found").clone();
     trackerStep.attached_files = params.get("attached_files").expect("attached_files param couldnt be found").clone();
     trackerStep.message_reference = params.get("message_reference").expect("message_reference param couldnt be found").clone();
+    trackerStep.origin = params.get("origin").cloned().unwrap_or_default();
 
     println!("{:?}", trackerStep);
 
@@ -200,6 +650,12 @@ pub fn get_last_tracker_step(tracker_id: &str) -> Result<TrackerStep, String>{
 
 
 pub fn create_new_step(step: &TrackerStep) -> Result<String, RedisError> {
+    create_step_with_outbox(step, &[])
+}
+
+// Creates the step and queues the messages to send in the outbox within the same transaction,
+// so a step is never left without its messages
+pub fn create_step_with_outbox(step: &TrackerStep, outbox_entries: &[&OutboxEntry]) -> Result<String, RedisError> {
     let client = create_client().unwrap();
     let mut con = client.get_connection().unwrap();
 
@@ -209,19 +665,18 @@ pub fn create_new_step(step: &TrackerStep) -> Result<String, RedisError> {
         Err(_) => panic!("SystemTime before UNIX EPOCH!"),
     };
 
-    // Create registry
-    let step_clone = step.clone();
-    let res: Result<String, RedisError> = con.hset_multiple(
-        format!("whatsapp-workflow:{}", &step.id),
-        &[
-            ("tracker_id", step_clone.clone().tracker_id),
-            ("timestamp", timestamp),
-            ("status", step_clone.status),
-            ("value", step_clone.value),
-            ("attached_files", step_clone.attached_files),
-            ("message_reference", step_clone.message_reference),
-        ],
-    );
+    let mut pipe = redis::pipe();
+    pipe.atomic();
+    add_step(&mut pipe, step, &timestamp);
+
+    // Entries are scored one millisecond apart so they're delivered in order
+    let first_attempt = timestamp.parse::<u64>().unwrap();
+    for (index, entry) in outbox_entries.iter().enumerate() {
+        pipe.hset(OUTBOX_ENTRIES_KEY, &entry.id, serde_json::to_string(entry).unwrap()).ignore();
+        pipe.zadd(OUTBOX_PENDING_KEY, &entry.id, first_attempt + index as u64).ignore();
+    }
+
+    let res: Result<(), RedisError> = pipe.query(&mut con);
 
     if res.is_err() {
         return Err(res.unwrap_err());
@@ -230,22 +685,237 @@ pub fn create_new_step(step: &TrackerStep) -> Result<String, RedisError> {
     return Ok(format!("whatsapp-workflow:{}", &step.id));
 }
 
-pub fn get_list(key: String, from: usize, to: usize) -> Result<Vec<String>, RedisError>{
+// Creates the synthetic steps of a tracker within the same transaction. They're timestamped one millisecond
+// apart before the current time, so the step created after them remains the last one
+pub fn create_steps(steps: &[TrackerStep]) -> Result<(), RedisError> {
     let client = create_client().unwrap();
     let mut con = client.get_connection().unwrap();
 
-    let list_res:RedisResult<Vec<String>> = con.lrange(key, from as isize, to as isize);
+    let now = match SystemTime::now().duration_since(UNIX_EPOCH) {
+        Ok(n) => n.as_millis() as u64,
+        Err(_) => panic!("SystemTime before UNIX EPOCH!"),
+    };
 
-    if list_res.is_err() {
-        return Err(list_res.unwrap_err())
+    let mut pipe = redis::pipe();
+    pipe.atomic();
+
+    for (index, step) in steps.iter().enumerate() {
+        add_step(&mut pipe, step, &(now - (steps.len() - index) as u64).to_string());
     }
 
-    Ok(list_res.unwrap())
+    pipe.query(&mut con)
 }
 
-pub fn get_list_size(key: &str)-> Result<u16, RedisError>{
-    let client = create_client().unwrap();
-    let mut con = client.get_connection().unwrap();
+// Adds the step registry and the tracker fields updated by it to the transaction
+fn add_step(pipe: &mut redis::Pipeline, step: &TrackerStep, timestamp: &str) {
+    // Create registry
+    let step_clone = step.clone();
+    pipe.hset_multiple(
+        format!("whatsapp-workflow:{}", &step.id),
+        &[
+            ("tracker_id", step_clone.clone().tracker_id),
+            ("timestamp", timestamp.to_string()),
+            ("status", step_clone.status),
+            ("value", step_clone.value),
+            ("attached_files", step_clone.attached_files),
+            ("message_reference", step_clone.message_reference),
+            ("origin", step_clone.origin),
+        ],
+    ).ignore();
+
+    // Register tracker activity, only trackers still active are updated(XX)
+    pipe.cmd("ZADD")
+        .arg(ACTIVE_TRACKERS_KEY)
+        .arg("XX")
+        .arg(timestamp)
+        .arg(&step.tracker_id)
+        .ignore();
+
+    // Reminder and retries are counted since the last step, current status and vehicle are kept for tracker searches
+    let mut tracker_fields: Vec<(&str, String)> = vec![
+        ("reminder_sent", "0".to_string()),
+        ("failed_attempts", "0".to_string()),
+        ("status", step.status.clone()),
+        ("updated", timestamp.to_string()),
+    ];
+
+    let selection = step.value.strip_suffix("-id").unwrap_or(&step.value).to_string();
+    if step.status == (FlowStatus::BrandSelected as u16).to_string() {
+        tracker_fields.push(("brand", selection));
+    } else if step.status == (FlowStatus::ModelSelected as u16).to_string() {
+        tracker_fields.push(("model", selection));
+    }
+
+    pipe.hset_multiple(format!("whatsapp-request:{}", &step.tracker_id), &tracker_fields).ignore();
+}
+
+// Ids of the outbox entries due for delivery
+pub fn get_due_outbox_entries(now: u64, limit: usize) -> Result<Vec<String>, String> {
+    let client = create_client().unwrap();
+    let mut con = client.get_connection().unwrap();
+
+    let res: RedisResult<Vec<String>> = con.zrangebyscore_limit(OUTBOX_PENDING_KEY, "-inf", now, 0, limit as isize);
+
+    if res.is_err() {
+        error!("Error obtaining due outbox entries: {}", res.as_ref().unwrap_err());
+        return Err(format!("Error obtaining due outbox entries: {}", res.as_ref().unwrap_err()))
+    }
+
+    Ok(res.unwrap())
+}
+
+// Takes the entry for delivery by postponing it until the lease expires, false if it was taken by another dispatcher
+pub fn claim_outbox_entry(entry_id: &str, now: u64, lease_until: u64) -> Result<bool, String> {
+    let client = create_client().unwrap();
+    let mut con = client.get_connection().unwrap();
+
+    let script = redis::Script::new(r"
+        local score = redis.call('ZSCORE', KEYS[1], ARGV[1])
+        if score and tonumber(score) <= tonumber(ARGV[2]) then
+            redis.call('ZADD', KEYS[1], ARGV[3], ARGV[1])
+            return 1
+        end
+        return 0
+    ");
+
+    let res: RedisResult<u8> = script.key(OUTBOX_PENDING_KEY).arg(entry_id).arg(now).arg(lease_until).invoke(&mut con);
+
+    if res.is_err() {
+        error!("Error claiming outbox entry: {}", res.as_ref().unwrap_err());
+        return Err(format!("Error claiming outbox entry: {}", res.as_ref().unwrap_err()))
+    }
+
+    Ok(res.unwrap() == 1)
+}
+
+pub fn get_outbox_entry(entry_id: &str) -> Result<OutboxEntry, String> {
+    let client = create_client().unwrap();
+    let mut con = client.get_connection().unwrap();
+
+    let res: RedisResult<Option<String>> = con.hget(OUTBOX_ENTRIES_KEY, entry_id);
+
+    if res.is_err() {
+        error!("Error obtaining outbox entry: {}", res.as_ref().unwrap_err());
+        return Err(format!("Error obtaining outbox entry: {}", res.as_ref().unwrap_err()))
+    }
+
+    match res.unwrap() {
+        Some(entry) => serde_json::from_str(&entry).map_err(|err| format!("Invalid outbox entry {}: {}", entry_id, err)),
+        None => Err(format!("Outbox entry {} not found", entry_id)),
+    }
+}
+
+// Removes a delivered entry from the outbox
+pub fn complete_outbox_entry(entry_id: &str) -> Result<(), String> {
+    let client = create_client().unwrap();
+    let mut con = client.get_connection().unwrap();
+
+    let res: RedisResult<()> = redis::pipe()
+        .atomic()
+        .zrem(OUTBOX_PENDING_KEY, entry_id).ignore()
+        .hdel(OUTBOX_ENTRIES_KEY, entry_id).ignore()
+        .query(&mut con);
+
+    if res.is_err() {
+        error!("Error completing outbox entry: {}", res.as_ref().unwrap_err());
+        return Err(format!("Error completing outbox entry: {}", res.as_ref().unwrap_err()))
+    }
+
+    Ok(())
+}
+
+// Stores the entry and schedules its next delivery attempt
+pub fn schedule_outbox_entry(entry: &OutboxEntry, next_attempt: u64) -> Result<(), String> {
+    let client = create_client().unwrap();
+    let mut con = client.get_connection().unwrap();
+
+    let res: RedisResult<()> = redis::pipe()
+        .atomic()
+        .hset(OUTBOX_ENTRIES_KEY, &entry.id, serde_json::to_string(entry).unwrap()).ignore()
+        .zrem(OUTBOX_DEAD_LETTER_KEY, &entry.id).ignore()
+        .zadd(OUTBOX_PENDING_KEY, &entry.id, next_attempt).ignore()
+        .query(&mut con);
+
+    if res.is_err() {
+        error!("Error scheduling outbox entry: {}", res.as_ref().unwrap_err());
+        return Err(format!("Error scheduling outbox entry: {}", res.as_ref().unwrap_err()))
+    }
+
+    Ok(())
+}
+
+// Stops retrying the entry, keeping it in the dead letter queue until it's re-driven
+pub fn dead_letter_outbox_entry(entry: &OutboxEntry, now: u64) -> Result<(), String> {
+    let client = create_client().unwrap();
+    let mut con = client.get_connection().unwrap();
+
+    let res: RedisResult<()> = redis::pipe()
+        .atomic()
+        .hset(OUTBOX_ENTRIES_KEY, &entry.id, serde_json::to_string(entry).unwrap()).ignore()
+        .zrem(OUTBOX_PENDING_KEY, &entry.id).ignore()
+        .zadd(OUTBOX_DEAD_LETTER_KEY, &entry.id, now).ignore()
+        .query(&mut con);
+
+    if res.is_err() {
+        error!("Error moving outbox entry to dead letters: {}", res.as_ref().unwrap_err());
+        return Err(format!("Error moving outbox entry to dead letters: {}", res.as_ref().unwrap_err()))
+    }
+
+    Ok(())
+}
+
+// Dead letter entry ids, oldest first, along with the total amount
+pub fn get_dead_letters(offset: usize, limit: usize) -> Result<(u64, Vec<String>), String> {
+    let client = create_client().unwrap();
+    let mut con = client.get_connection().unwrap();
+
+    let total: RedisResult<u64> = con.zcard(OUTBOX_DEAD_LETTER_KEY);
+
+    if total.is_err() {
+        error!("Error obtaining dead letters: {}", total.as_ref().unwrap_err());
+        return Err(format!("Error obtaining dead letters: {}", total.as_ref().unwrap_err()))
+    }
+
+    let res: RedisResult<Vec<String>> = con.zrange(OUTBOX_DEAD_LETTER_KEY, offset as isize, (offset + limit) as isize - 1);
+
+    if res.is_err() {
+        error!("Error obtaining dead letters: {}", res.as_ref().unwrap_err());
+        return Err(format!("Error obtaining dead letters: {}", res.as_ref().unwrap_err()))
+    }
+
+    Ok((total.unwrap(), res.unwrap()))
+}
+
+pub fn is_dead_letter(entry_id: &str) -> Result<bool, String> {
+    let client = create_client().unwrap();
+    let mut con = client.get_connection().unwrap();
+
+    let res: RedisResult<Option<u64>> = con.zscore(OUTBOX_DEAD_LETTER_KEY, entry_id);
+
+    if res.is_err() {
+        error!("Error obtaining dead letter: {}", res.as_ref().unwrap_err());
+        return Err(format!("Error obtaining dead letter: {}", res.as_ref().unwrap_err()))
+    }
+
+    Ok(res.unwrap().is_some())
+}
+
+pub fn get_list(key: String, from: usize, to: usize) -> Result<Vec<String>, RedisError>{
+    let client = create_client().unwrap();
+    let mut con = client.get_connection().unwrap();
+
+    let list_res:RedisResult<Vec<String>> = con.lrange(key, from as isize, to as isize);
+
+    if list_res.is_err() {
+        return Err(list_res.unwrap_err())
+    }
+
+    Ok(list_res.unwrap())
+}
+
+pub fn get_list_size(key: &str)-> Result<u16, RedisError>{
+    let client = create_client().unwrap();
+    let mut con = client.get_connection().unwrap();
 
     let list_size:RedisResult<u16> = con.llen(key);
 
@@ -256,20 +926,36 @@ pub fn get_list_size(key: &str)-> Result<u16, RedisError>{
     Ok(list_size.unwrap())
 }
 
-pub fn publish_message(
-    message: &MessageLog,
+pub fn publish_message<T: Serialize>(
+    message: &T,
     phone_number: &String,
+    event: &WorkflowEvent,
 ) -> Result<String, Box<dyn Error>> {
     let client = create_client()?;
     let mut con = client.get_connection()?;
-    let _: () = con
-        .publish(
-            format!("whatsapp-notification:{}", phone_number),
-            serde_json::to_string(message).unwrap(),
-        )
-        .expect("err");
 
-    Ok("OK".to_string())
+    let payload = serde_json::to_string(message)?;
+
+    // Events are kept in a stream so consumers that weren't subscribed when it was published can still read them
+    let stream = std::env::var("WORKFLOW_EVENTS_STREAM").unwrap_or(WORKFLOW_EVENTS_STREAM.to_string());
+    let max_length = get_env_setting("WORKFLOW_EVENTS_MAXLEN", WORKFLOW_EVENTS_MAXLEN as u64) as usize;
+
+    let entry_id: String = con.xadd_maxlen(
+        stream,
+        StreamMaxlen::Approx(max_length),
+        "*",
+        &[
+            ("event_type", event.event_type.as_str()),
+            ("tracker_id", event.tracker_id.as_str()),
+            ("step_id", event.step_id.as_str()),
+            ("phone_number", phone_number.as_str()),
+            ("payload", payload.as_str()),
+        ],
+    )?;
+
+    let _: () = con.publish(format!("whatsapp-notification:{}", phone_number), payload)?;
+
+    Ok(entry_id)
 }
 
 
@@ -286,6 +972,378 @@ pub fn reset_user_mode(phone_number:&str) -> Result<(), String>{
 
     Ok(())
 }
+pub fn set_user_mode(phone_number:&str, mode: u8) -> Result<(), String>{
+    let client = create_client().unwrap();
+    let mut con = client.get_connection().unwrap();
+
+    let res: RedisResult<u16> = con.hset(format!("selected-mode:{}", phone_number), "mode", mode.to_string());
+
+    if res.is_err() {
+        error!("Error setting user mode: {}", res.as_ref().unwrap_err());
+        return Err(format!("Error setting user mode: {}", res.as_ref().unwrap_err()))
+    }
+
+    Ok(())
+}
+
+// Takes the lock until it expires, only one instance gets it while it's held
+pub fn try_lock(name: &str, lease_millis: u64) -> Result<bool, String>{
+    let client = create_client().unwrap();
+    let mut con = client.get_connection().unwrap();
+
+    let res: RedisResult<Option<String>> = redis::cmd("SET")
+        .arg(format!("lock:{}", name))
+        .arg(uuid::Uuid::new_v4().to_string())
+        .arg("NX")
+        .arg("PX")
+        .arg(lease_millis)
+        .query(&mut con);
+
+    if res.is_err() {
+        return Err(format!("Error taking lock {}: {}", name, res.unwrap_err()))
+    }
+
+    Ok(res.unwrap().is_some())
+}
+
+// Marks the webhook message as processed, false if it was already marked
+pub fn mark_message_processed(message_id: &str) -> Result<bool, String>{
+    let client = create_client().unwrap();
+    let mut con = client.get_connection().unwrap();
+
+    let res: RedisResult<Option<String>> = redis::cmd("SET")
+        .arg(format!("processed-message:{}", message_id))
+        .arg("1")
+        .arg("NX")
+        .arg("EX")
+        .arg(PROCESSED_MESSAGE_TTL_SECONDS)
+        .query(&mut con);
+
+    if res.is_err() {
+        return Err(format!("Error marking message {} as processed: {}", message_id, res.unwrap_err()))
+    }
+
+    Ok(res.unwrap().is_some())
+}
+
+// Clears the processed mark so a redelivery of a message that failed is handled again
+pub fn unmark_message_processed(message_id: &str) -> Result<(), String>{
+    let client = create_client().unwrap();
+    let mut con = client.get_connection().unwrap();
+
+    let res: RedisResult<()> = con.del(format!("processed-message:{}", message_id));
+
+    if res.is_err() {
+        return Err(format!("Error clearing processed mark of message {}: {}", message_id, res.unwrap_err()))
+    }
+
+    Ok(())
+}
+
+// Time of the last message received from the user, used for the customer service window
+pub fn set_last_inbound(phone_number: &str, timestamp: u64) -> Result<(), String>{
+    let client = create_client().unwrap();
+    let mut con = client.get_connection().unwrap();
+
+    let res: RedisResult<()> = con.set(format!("last-inbound:{}", phone_number), timestamp);
+
+    if res.is_err() {
+        error!("Error setting last inbound message: {}", res.as_ref().unwrap_err());
+        return Err(format!("Error setting last inbound message: {}", res.as_ref().unwrap_err()))
+    }
+
+    Ok(())
+}
+
+pub fn get_last_inbound(phone_number: &str) -> Result<Option<u64>, String>{
+    let client = create_client().unwrap();
+    let mut con = client.get_connection().unwrap();
+
+    let res: RedisResult<Option<u64>> = con.get(format!("last-inbound:{}", phone_number));
+
+    if res.is_err() {
+        return Err(format!("Error obtaining last inbound message: {}", res.unwrap_err()))
+    }
+
+    Ok(res.unwrap())
+}
+
+// Updates the customer profile with a received message, creating it on the first one
+pub fn touch_customer_profile(phone_number: &str, name: Option<&str>, timestamp: u64) -> Result<(), String>{
+    let client = create_client().unwrap();
+    let mut con = client.get_connection().unwrap();
+
+    let key = format!("customer-profile:{}", phone_number);
+    let mut pipe = redis::pipe();
+    pipe.atomic()
+        .hset_nx(&key, "first_seen", timestamp).ignore()
+        .hset_nx(&key, "language", DEFAULT_LANGUAGE).ignore()
+        .hset(&key, "last_seen", timestamp).ignore();
+
+    if name.is_some() {
+        pipe.hset(&key, "name", name.unwrap()).ignore();
+    }
+
+    let res: RedisResult<()> = pipe.query(&mut con);
+
+    if res.is_err() {
+        error!("Error updating customer profile: {}", res.as_ref().unwrap_err());
+        return Err(format!("Error updating customer profile: {}", res.as_ref().unwrap_err()))
+    }
+
+    Ok(())
+}
+
+pub fn increment_customer_requests(phone_number: &str) -> Result<(), String>{
+    let client = create_client().unwrap();
+    let mut con = client.get_connection().unwrap();
+
+    let res: RedisResult<u32> = con.hincr(format!("customer-profile:{}", phone_number), "requests", 1);
+
+    if res.is_err() {
+        error!("Error updating customer requests: {}", res.as_ref().unwrap_err());
+        return Err(format!("Error updating customer requests: {}", res.as_ref().unwrap_err()))
+    }
+
+    Ok(())
+}
+
+pub fn get_customer_profile(phone_number: &str) -> Result<CustomerProfile, String>{
+    let client = create_client().unwrap();
+    let mut con = client.get_connection().unwrap();
+
+    let res: RedisResult<HashMap<String, String>> = con.hgetall(format!("customer-profile:{}", phone_number));
+
+    if res.is_err() {
+        return Err(format!("Error obtaining customer profile: {}", res.unwrap_err()))
+    }
+
+    let fields = res.unwrap();
+
+    if fields.is_empty() {
+        return Err(format!("No records found for customer {}", phone_number))
+    }
+
+    Ok(CustomerProfile::from_fields(phone_number, &fields))
+}
+
+// Saves the vehicle of a completed request in the customer profile, vehicles are stored as JSON
+pub fn save_customer_vehicle(phone_number: &str, vehicle: SavedVehicle) -> Result<(), String>{
+    let client = create_client().unwrap();
+    let mut con = client.get_connection().unwrap();
+
+    let key = format!("customer-profile:{}", phone_number);
+    let res: RedisResult<Option<String>> = con.hget(&key, "vehicles");
+
+    if res.is_err() {
+        return Err(format!("Error obtaining customer vehicles: {}", res.unwrap_err()))
+    }
+
+    let mut vehicles: Vec<SavedVehicle> = res.unwrap().and_then(|vehicles| serde_json::from_str(&vehicles).ok()).unwrap_or_default();
+    vehicle.add_to(&mut vehicles);
+
+    let res: RedisResult<()> = con.hset(&key, "vehicles", serde_json::to_string(&vehicles).unwrap());
+
+    if res.is_err() {
+        error!("Error saving customer vehicle: {}", res.as_ref().unwrap_err());
+        return Err(format!("Error saving customer vehicle: {}", res.as_ref().unwrap_err()))
+    }
+
+    Ok(())
+}
+
+// Records a message sent for a tracker step so its webhook statuses can be correlated, along with the entry to send it again
+pub fn save_message_delivery(reference: &str, entry: &OutboxEntry, phone_number: &str, timestamp: u64) -> Result<(), String> {
+    let client = create_client().unwrap();
+    let mut con = client.get_connection().unwrap();
+
+    let key = format!("message-status:{}", reference);
+    let fields = [
+        ("tracker_id", entry.tracker_id.clone()),
+        ("step_id", entry.step_id.clone()),
+        ("phone_number", phone_number.to_string()),
+        ("accepted_at", timestamp.to_string()),
+        ("entry", serde_json::to_string(entry).unwrap()),
+    ];
+
+    let res: RedisResult<()> = redis::pipe()
+        .atomic()
+        .hset_multiple(&key, &fields).ignore()
+        .expire(&key, MESSAGE_DELIVERY_TTL_SECONDS).ignore()
+        .query(&mut con);
+
+    if res.is_err() {
+        error!("Error saving message delivery: {}", res.as_ref().unwrap_err());
+        return Err(format!("Error saving message delivery: {}", res.as_ref().unwrap_err()))
+    }
+
+    Ok(())
+}
+
+// Stores the time the status was reached, None for unknown statuses or messages that weren't sent for a tracker step
+pub fn update_message_delivery(reference: &str, status: &str, timestamp: u64, error: Option<&str>) -> Result<Option<MessageDelivery>, String> {
+    if !DELIVERY_STATUSES.contains(&status) {
+        info!("Skipping unknown delivery status {}", status);
+        return Ok(None)
+    }
+
+    let client = create_client().unwrap();
+    let mut con = client.get_connection().unwrap();
+
+    let key = format!("message-status:{}", reference);
+    let exists: RedisResult<bool> = con.exists(&key);
+
+    if exists.is_err() {
+        return Err(format!("Error obtaining message delivery: {}", exists.unwrap_err()))
+    }
+
+    if !exists.unwrap() {
+        return Ok(None)
+    }
+
+    let mut fields = vec![(format!("{}_at", status), timestamp.to_string())];
+
+    if error.is_some() {
+        fields.push(("error".to_string(), error.unwrap().to_string()));
+    }
+
+    let res: RedisResult<()> = con.hset_multiple(&key, &fields);
+
+    if res.is_err() {
+        error!("Error updating message delivery: {}", res.as_ref().unwrap_err());
+        return Err(format!("Error updating message delivery: {}", res.as_ref().unwrap_err()))
+    }
+
+    get_message_delivery(reference)
+}
+
+pub fn get_message_delivery(reference: &str) -> Result<Option<MessageDelivery>, String> {
+    let client = create_client().unwrap();
+    let mut con = client.get_connection().unwrap();
+
+    let res: RedisResult<HashMap<String, String>> = con.hgetall(format!("message-status:{}", reference));
+
+    if res.is_err() {
+        return Err(format!("Error obtaining message delivery: {}", res.unwrap_err()))
+    }
+
+    let fields = res.unwrap();
+
+    if fields.is_empty() {
+        return Ok(None)
+    }
+
+    Ok(Some(MessageDelivery::from_fields(reference, &fields)))
+}
+
+// Outbox entry the message was sent from
+// Marks the failed message as handled, false if a previous failure status already handled it
+pub fn mark_delivery_redelivered(reference: &str, timestamp: u64) -> Result<bool, String> {
+    let client = create_client().unwrap();
+    let mut con = client.get_connection().unwrap();
+
+    let res: RedisResult<bool> = con.hset_nx(format!("message-status:{}", reference), "redelivered_at", timestamp);
+
+    if res.is_err() {
+        return Err(format!("Error marking message {} as redelivered: {}", reference, res.unwrap_err()))
+    }
+
+    Ok(res.unwrap())
+}
+
+pub fn get_delivery_entry(reference: &str) -> Result<OutboxEntry, String> {
+    let client = create_client().unwrap();
+    let mut con = client.get_connection().unwrap();
+
+    let res: RedisResult<Option<String>> = con.hget(format!("message-status:{}", reference), "entry");
+
+    if res.is_err() {
+        return Err(format!("Error obtaining delivery entry: {}", res.unwrap_err()))
+    }
+
+    let entry = res.unwrap();
+
+    if entry.is_none() {
+        return Err(format!("No records found for message {}", reference))
+    }
+
+    serde_json::from_str(&entry.unwrap()).map_err(|err| format!("Invalid delivery entry: {}", err))
+}
+
+pub fn save_quote(tracker_id: &str, quote: &SupplierQuote) -> Result<(), String>{
+    let client = create_client().unwrap();
+    let mut con = client.get_connection().unwrap();
+
+    let res: RedisResult<u16> = con.hset(format!("tracker-quotes:{}", tracker_id), &quote.id, serde_json::to_string(quote).unwrap());
+
+    if res.is_err() {
+        error!("Error saving quote: {}", res.as_ref().unwrap_err());
+        return Err(format!("Error saving quote: {}", res.as_ref().unwrap_err()))
+    }
+
+    Ok(())
+}
+
+// Quotes attached to the tracker, cheapest first
+pub fn get_quotes(tracker_id: &str) -> Result<Vec<SupplierQuote>, String>{
+    let client = create_client().unwrap();
+    let mut con = client.get_connection().unwrap();
+
+    let res: RedisResult<HashMap<String, String>> = con.hgetall(format!("tracker-quotes:{}", tracker_id));
+
+    if res.is_err() {
+        error!("Error obtaining quotes: {}", res.as_ref().unwrap_err());
+        return Err(format!("Error obtaining quotes: {}", res.as_ref().unwrap_err()))
+    }
+
+    let mut quotes: Vec<SupplierQuote> = res.unwrap().values()
+        .filter_map(|quote| serde_json::from_str(quote).ok())
+        .collect();
+    quotes.sort_by_key(|quote| quote.price);
+
+    Ok(quotes)
+}
+
+pub fn get_quote(tracker_id: &str, quote_id: &str) -> Result<SupplierQuote, String>{
+    let client = create_client().unwrap();
+    let mut con = client.get_connection().unwrap();
+
+    let res: RedisResult<Option<String>> = con.hget(format!("tracker-quotes:{}", tracker_id), quote_id);
+
+    if res.is_err() {
+        error!("Error obtaining quote: {}", res.as_ref().unwrap_err());
+        return Err(format!("Error obtaining quote: {}", res.as_ref().unwrap_err()))
+    }
+
+    match res.unwrap() {
+        Some(quote) => serde_json::from_str(&quote).map_err(|err| format!("Invalid quote {}: {}", quote_id, err)),
+        None => Err(format!("Quote {} not found", quote_id)),
+    }
+}
+
+// Obtains every step of the tracker ordered by creation
+pub fn get_all_steps(tracker_id: &str) -> Result<Vec<TrackerStep>, String> {
+    let mut steps: Vec<TrackerStep> = vec![];
+
+    loop {
+        let page = get_tracker_steps_page(tracker_id, steps.len(), STEPS_PAGE_SIZE);
+
+        if page.is_err() {
+            return Err(page.unwrap_err())
+        }
+
+        let (total, page_steps) = page.unwrap();
+        let page_size = page_steps.len();
+        steps.extend(page_steps);
+
+        if page_size == 0 || steps.len() as u64 >= total {
+            break
+        }
+    }
+
+    Ok(steps)
+}
+
 pub fn get_step_by_status(tracker_id: &str, status: &str) -> Result<TrackerStep, String>{
     let client = create_client().unwrap();
     let mut con = client.get_connection().unwrap();
@@ -293,6 +1351,12 @@ pub fn get_step_by_status(tracker_id: &str, status: &str) -> Result<TrackerStep,
     let res:RedisResult<Vec<(u32, String, Vec<String>)>> = redis::cmd("FT.SEARCH")
         .arg("trackerSteps")
         .arg(format!("@tracker_id:{} @status:{}", tracker_id, status))
+        .arg("SORTBY")
+        .arg("timestamp")
+        .arg("DESC")
+        .arg("LIMIT")
+        .arg("0")
+        .arg("1")
         .query(&mut con);
 
 
@@ -315,6 +1379,7 @@ pub fn get_step_by_status(tracker_id: &str, status: &str) -> Result<TrackerStep,
         value: "".to_string(),
         attached_files: "".to_string(),
         message_reference: "".to_string(),
+        origin: "".to_string(),
     };
 
     let mut params: HashMap<String, String> = HashMap::new();
@@ -337,26 +1402,60 @@ pub fn get_step_by_status(tracker_id: &str, status: &str) -> Result<TrackerStep,
     trackerStep.value = params.get("value").expect("value param couldnt be found").clone();
     trackerStep.attached_files = params.get("attached_files").expect("attached_files param couldnt be found").clone();
     trackerStep.message_reference = params.get("message_reference").expect("message_reference param couldnt be found").clone();
+    trackerStep.origin = params.get("origin").cloned().unwrap_or_default();
 
     println!("{:?}", trackerStep);
 
     Ok(trackerStep)
 }
 
-pub fn get_all_tracker_steps(tracker_id: &str) -> Result<Value, String>{
+// Obtains a page of the tracker steps ordered by creation, along with the total of steps
+pub fn get_tracker_steps_page(tracker_id: &str, offset: usize, limit: usize) -> Result<(u64, Vec<TrackerStep>), String>{
     let client = create_client().unwrap();
     let mut con = client.get_connection().unwrap();
 
     let res:RedisResult<Value> = redis::cmd("FT.SEARCH")
         .arg("trackerSteps")
         .arg(format!("@tracker_id:{tracker_id}"))
+        .arg("SORTBY")
+        .arg("timestamp")
+        .arg("ASC")
+        .arg("LIMIT")
+        .arg(offset)
+        .arg(limit)
         .query(&mut con);
 
     if res.is_err(){
         return Err(res.unwrap_err().to_string())
     }
 
-    Ok(res.unwrap())
+    let results = parse_search_results(&res.unwrap());
+
+    if results.is_err() {
+        return Err(results.unwrap_err())
+    }
+
+    let (total, registers) = results.unwrap();
+    let steps = registers.iter()
+        .map(|(key, params)| parse_step(&key.replace("whatsapp-workflow:", ""), params))
+        .collect();
+
+    Ok((total, steps))
+}
+
+fn parse_step(step_id: &str, params: &HashMap<String, String>) -> TrackerStep {
+    TrackerStep{
+        tracker_id: params.get("tracker_id").expect("tracker_id param couldnt be found").clone(),
+        timestamp: params.get("timestamp").expect("timestamp param couldnt be found").clone(),
+        id: step_id.to_string(),
+        status: params.get("status").expect("status param couldnt be found").clone(),
+        value: params.get("value").expect("value param couldnt be found").clone(),
+        attached_files: params.get("attached_files").expect("attached_files param couldnt be found").clone(),
+        message_reference: params.get("message_reference").expect("message_reference param couldnt be found").clone(),
+
+        // Not present on steps created before origins were introduced
+        origin: params.get("origin").cloned().unwrap_or_default(),
+    }
 }
 
 
@@ -1,277 +1,274 @@
+use std::collections::HashSet;
 use std::time::{SystemTime, UNIX_EPOCH};
 use actix_web::web::Query;
 use aws_config::SdkConfig;
-use fizzy_commons::shared_structs::MessageRequest;
+use fizzy_commons::shared_structs::{ButtonMessage, Choice, MessageContent, MessageRequest};
 use log::Level::Info;
 use redis::RedisError;
-use redis::Value::Bulk;
 use regex::Regex;
 use serde::de::Unexpected::Str;
-use crate::redis::{create_new_step, create_new_tracker, get_all_tracker_steps, get_last_tracker, get_last_tracker_step, get_user_message, publish_message};
-use crate::structs::{Event, MessageLog, ModifiedReference, StandardResponse, TrackerStep};
+use crate::redis::{create_new_step, get_all_steps, get_user_mode, mark_message_processed, unmark_message_processed, save_user_message, create_new_tracker, create_step_with_outbox, get_dead_letters as get_dead_letter_ids, get_outbox_entry, is_dead_letter, schedule_outbox_entry, get_last_tracker, get_last_tracker_step, get_message_delivery, get_outgoing_messages, get_tracker, get_tracker_steps_page, increment_tracker_field, publish_agent_notification, publish_message, save_quote, set_tracker_field, set_last_inbound, set_tracker_state, set_user_mode, touch_customer_profile, increment_customer_requests, get_customer_profile as get_profile, save_customer_vehicle, update_message_delivery};
+use crate::structs::{AgentNotification, AgentReply, CustomerProfile, Event, HandoffRelease, InboundMessage, Message, MessageLog, ModifiedReference, OutboxEntry, OutboxPage, OutboxParams, PartClassification, PartRequest, PartRequestSubmitted, QuotesRequest, RequestTracker, SavedVehicle, StandardResponse, Status, TrackerPage, TrackerParam, TrackerSearchParams, TrackerStep, TrackerStepView, TrackerStepsPage, TrackerStepsResponse, TrackerSummary, TranscriptEntry, WorkflowEvent};
 use uuid::Uuid;
-use crate::constants::{FlowStatus, MessageType, ResponseStatus};
-use crate::step_functions::execute_function;
-use crate::tools::{find_message_type, get_message_content, send_message, upload_image};
-
-
-pub async fn outgoing_message(log: MessageLog) -> Result<StandardResponse, StandardResponse> {
+use enum_iterator::all;
+use crate::constants::{AGENT_COMMAND, FlowStatus, MessageType, PART_CLASSIFICATION_SYSTEM_ID, PART_REQUEST_SUBMITTED_VERSION, ResponseStatus, StepOrigin, SUPPLIER_SYSTEM_ID, SYSTEM_ID, TrackerState};
+use crate::step_functions::{execute_function, get_step_prompt};
+use crate::tools::{escape_query_value, get_env_setting, is_within_service_window, parse_ticket_number, upload_image};
+use crate::outbox::{queue_message, redeliver_failed_message};
+use crate::channel::get_channel;
+
+// Media agents can send along their replies
+const AGENT_MEDIA_TYPES: [&str; 4] = ["image", "document", "audio", "video"];
+
+// Contact name is passed by callers that received the user message, it's recorded in the customer profile
+pub async fn outgoing_message(log: MessageLog, contact_name: Option<&str>) -> Result<StandardResponse, StandardResponse> {
     let mut response: StandardResponse = StandardResponse::new();
     let mut errors = vec![];
     let mut references = vec![];
-    let first_step = FlowStatus::FlowStarted as u16;
 
     info!("log: {:?}", serde_json::to_string_pretty(&log).unwrap());
 
     if log.origin_system == "1" {
         info!("Message from whatsapp-manager");
         // If message comes from whatsapp manager mode selection
+        record_inbound(&log, contact_name);
+
+        // Offer the user to continue an unfinished request instead of silently abandoning it
+        let unfinished_tracker = get_unfinished_tracker(&log.phone_number);
+
+        if unfinished_tracker.is_some() {
+            info!("Found unfinished tracker for phone number, sending resume prompt");
+
+            let prompt_res = send_resume_prompt(unfinished_tracker.as_ref().unwrap());
+
+            if prompt_res.is_err() {
+                error!("Error sending resume prompt {}", prompt_res.as_ref().unwrap_err());
+                errors.push(format!("Error sending resume prompt {}", prompt_res.as_ref().unwrap_err()));
+
+                response.errors = Some(errors);
+                return Err(response)
+            }
 
-        // Create new workflow
-        // Removes hyphen for limitation on query syntax
-        let uuid_tracker = Uuid::new_v4().to_string().replace("-", "");
+            response.errors = None;
+            response.references = references;
+            return Ok(response)
+        }
 
-        let created = create_new_tracker(&uuid_tracker, &log.phone_number);
+        let created = start_new_tracker(&log);
 
         if created.is_err() {
-            error!("Error creating tracker for request");
-            errors.push("Error creating tracker for request".to_string());
+            errors.push(created.unwrap_err());
 
             response.errors = Some(errors);
             return Err(response)
         }
-        references.push(ModifiedReference {
-            system: "REDIS".to_string(),
-            reference: created.unwrap().to_string(),
-        });
-
-        let timestamp = match SystemTime::now().duration_since(UNIX_EPOCH) {
-            Ok(n) => n.as_millis().to_string(),
-            Err(_) => panic!("SystemTime before UNIX EPOCH!"),
-        };
+        references.extend(created.unwrap());
 
-        // Create initial tracker step
-        let uuid_step = Uuid::new_v4().to_string().replace("-", "");
-        let initial_step = TrackerStep{
-            tracker_id: uuid_tracker,
-            timestamp: timestamp.clone(), // It's set on creation function
-            id: uuid_step,
-            status: first_step.to_string(),
-            value: "".to_string(),
-            attached_files: "".to_string(),
-            message_reference: String::from(&log.register_id),
-        };
+    } else if &log.origin_system == "3" {
 
-        // Create new step register
-        let step_res = create_new_step(&initial_step);
+        info!("Message from own system");
+        // If message comes from same system
 
+        let tracker = get_last_tracker(&log.phone_number);
 
-        if step_res.is_err() {
-            error!("Error creating initial request for tracker");
-            errors.push("Error creating initial request for tracker".to_string());
+        if tracker.is_err() {
+            error!("Error obtaining last tracker {}", tracker.as_ref().unwrap_err().as_str());
+            errors.push(format!("Error obtaining last tracker {}", tracker.as_ref().unwrap_err().as_str()));
 
             response.errors = Some(errors);
             return Err(response)
         }
-        references.push(ModifiedReference {
-            system: "REDIS".to_string(),
-            reference: step_res.unwrap().to_string(),
-        });
 
+        return advance_flow(&log, &tracker.unwrap().id).await
 
-        // Send message from step
-        if FlowStatus::get_from_value(&first_step.to_string()).value().successful_response.is_none() {
+    }else{
+        error!("Origin system not supported: {}", &log.origin_system);
+        errors.push(format!("Origin system not supported: {}", &log.origin_system));
 
-            // Execute message sending
-            let message = FlowStatus::get_from_value(&first_step.to_string()).value().successful_response.unwrap();
-            let message_res: Result<StandardResponse, String> = send_message(message);
+        response.errors = Some(errors);
+        return Err(response)
+    }
 
-            // If error sending message
-            if message_res.is_err() {
-                let err: String = message_res.unwrap_err();
-                error!("Error sending message {}", err);
-                errors.push(format!("Error sending message {}", err));
 
-                response.errors = Some(errors);
-                return Err(response)
-            }
 
-            // Publish message
 
-            let new_log = MessageLog{
-                timestamp: timestamp.clone(),
-                destination_systems: vec!["3".to_string()],
-                origin_system: "3".to_string(),
-                phone_number: log.phone_number.to_string(),
-                origin: "OUTGOING".to_string(),
-                register_id: String::from(&log.register_id),
-            };
+    response.errors = None;
+    response.references = references;
+    Ok(response)
+}
 
 
-            let publish_res = publish_message(&new_log, &log.phone_number);
+// Continues the flow of the tracker after a message of this system was sent, executing the next step if it doesn't expect a user response
+async fn advance_flow(log: &MessageLog, tracker_id: &str) -> Result<StandardResponse, StandardResponse> {
+    let mut response: StandardResponse = StandardResponse::new();
+    let mut errors: Vec<String> = vec![];
+    let mut references: Vec<ModifiedReference> = vec![];
 
-            // If error sending message
-            if publish_res.is_err() {
-                error!("Error publishing message {}", publish_res.as_ref().unwrap_err());
-                errors.push(format!("Error publishing message {}", publish_res.as_ref().unwrap_err()));
+    let tracker = get_tracker(tracker_id);
 
-                response.errors = Some(errors);
-                return Err(response)
-            }
+    if tracker.is_err() {
+        return Err(tracker_error(tracker_id, tracker.unwrap_err()))
+    }
 
-        }
+    // Flow is paused while an agent handles the conversation
+    if tracker.as_ref().unwrap().state == TrackerState::Handoff.value() {
+        info!("Tracker handed off to an agent, skipping flow");
 
+        response.errors = None;
+        response.references = references;
+        return Ok(response)
+    }
 
-    } else if &log.origin_system == "3" {
+    let step = get_last_tracker_step(&tracker.as_ref().unwrap().id);
 
-        info!("Message from own system");
-        // If message comes from same system
+    if step.is_err() {
+        error!("Error obtaining last step {}", step.as_ref().unwrap_err().as_str());
+        errors.push(format!("Error obtaining last step {}", step.as_ref().unwrap_err().as_str()));
 
-        let tracker = get_last_tracker(&log.phone_number);
+        response.errors = Some(errors);
+        return Err(response)
+    }
 
-        if tracker.is_err() {
-            error!("Error obtaining last tracker {}", tracker.as_ref().unwrap_err().as_str());
-            errors.push(format!("Error obtaining last tracker {}", tracker.as_ref().unwrap_err().as_str()));
+    let status = FlowStatus::get_from_value(&step.as_ref().unwrap().status);
 
-            response.errors = Some(errors);
-            return Err(response)
-        }
 
-        let step = get_last_tracker_step(&tracker.as_ref().unwrap().id);
+    // If request is completed publish the part request so the request classification system can process it
+    if status == FlowStatus::RequestAccepted {
+
+        let timestamp = match SystemTime::now().duration_since(UNIX_EPOCH) {
+            Ok(n) => n.as_millis().to_string(),
+            Err(_) => panic!("SystemTime before UNIX EPOCH!"),
+        };
+
+        let part_request = build_part_request(tracker.as_ref().unwrap());
 
-        if step.is_err() {
-            error!("Error obtaining last step {}", step.as_ref().unwrap_err().as_str());
-            errors.push(format!("Error obtaining last step {}", step.as_ref().unwrap_err().as_str()));
+        if part_request.is_err() {
+            error!("Error building part request {}", part_request.as_ref().unwrap_err());
+            errors.push(format!("Error building part request {}", part_request.unwrap_err()));
 
             response.errors = Some(errors);
             return Err(response)
         }
 
-        let status = FlowStatus::get_from_value(&step.unwrap().status);
+        let part_request = part_request.unwrap();
 
+        // Vehicle is offered again on the customer next requests
+        if let (Some(make), Some(model), Some(identifier)) = (&part_request.make, &part_request.model, &part_request.identifier) {
+            let vehicle = SavedVehicle{ make: make.clone(), model: model.clone(), identifier: identifier.clone() };
+            let res = save_customer_vehicle(&log.phone_number, vehicle);
 
-        // If request is completed generate a log to channel so the request classification system can be notified
-        if status == FlowStatus::RequestAccepted {
+            if res.is_err() {
+                error!("Error saving customer vehicle {}", res.unwrap_err());
+            }
+        }
+
+        let classification_system = std::env::var("CLASSIFICATION_SYSTEM_ID").unwrap_or(PART_CLASSIFICATION_SYSTEM_ID.to_string());
 
-            let timestamp = match SystemTime::now().duration_since(UNIX_EPOCH) {
-                Ok(n) => n.as_millis().to_string(),
-                Err(_) => panic!("SystemTime before UNIX EPOCH!"),
-            };
+        // Republished requests keep the same event id so consumers can discard duplicates
+        let event_name = format!("{}:{}", part_request.tracker_id, PART_REQUEST_SUBMITTED_VERSION);
 
-            let accepted_log = MessageLog {
+        let submitted_event = PartRequestSubmitted {
+            event_type: "PartRequestSubmitted".to_string(),
+            version: PART_REQUEST_SUBMITTED_VERSION,
+            event_id: Uuid::new_v5(&Uuid::NAMESPACE_OID, event_name.as_bytes()).to_string(),
+            log: MessageLog {
                 timestamp: timestamp,
-                destination_systems: vec![5.to_string()],
-                origin_system: "3".to_string(),
+                destination_systems: vec![classification_system],
+                origin_system: SYSTEM_ID.to_string(),
                 phone_number: log.phone_number.clone(),
                 origin: "OUTGOING".to_string(),
                 register_id: log.register_id.clone(),
-            };
-
-            publish_message(&accepted_log, &log.phone_number);
-
-            response.errors = None;
-            response.references = references;
-            return Ok(response)
-
-        }
-
-        info!("Current flow status: {:?}", status);
-        let next_step_id = if status.value().next_step.is_some() {
-             (status.value().next_step.unwrap() as u16).to_string()
-        }else{
-            (status as u16).to_string()
+            },
+            part_request,
         };
 
-        let next_step = FlowStatus::get_from_value(&next_step_id.to_string());
+        info!("Submitting part request {} to classification system", &submitted_event.event_id);
+        let event = WorkflowEvent::new("PartRequestSubmitted", &tracker.as_ref().unwrap().id, &step.as_ref().unwrap().id);
+        let publish_res = publish_message(&submitted_event, &log.phone_number, &event);
 
-        info!("Next step is {next_step:?}");
-        if next_step.value().required_response.is_none() {
+        if publish_res.is_err() {
+            error!("Error publishing part request {}", publish_res.as_ref().unwrap_err());
+            errors.push(format!("Error publishing part request {}", publish_res.unwrap_err()));
 
-            info!("Step {next_step:?} requires response");
-
-
-            let uuid_step = Uuid::new_v4().to_string().replace("-", "");
+            response.errors = Some(errors);
+            return Err(response)
+        }
 
-            let timestamp = match SystemTime::now().duration_since(UNIX_EPOCH) {
-                Ok(n) => n.as_millis().to_string(),
-                Err(_) => panic!("SystemTime before UNIX EPOCH!"),
-            };
+        response.errors = None;
+        response.references = references;
+        return Ok(response)
 
-            let mut new_step = TrackerStep{
-                tracker_id: String::from(&tracker.as_ref().unwrap().id),
-                timestamp: timestamp.clone(),
-                id: uuid_step,
-                status: (next_step as u16).to_string(),
-                value: "".to_string(),
-                attached_files: "".to_string(),
-                message_reference: String::from(&log.register_id.clone()),
-            };
+    }
 
-            info!("Executing {next_step:?} handler function");
-            let parsed_message: Result<MessageRequest, String> = execute_function(&mut new_step, next_step, &log, "").await;
+    info!("Current flow status: {:?}", status);
+    let next_step = get_next_step(tracker.as_ref().unwrap(), status).unwrap_or(status);
 
-            if parsed_message.is_err() {
-                // errors.push(parsed_message.unwrap_err());
+    info!("Next step is {next_step:?}");
+    if next_step.value().required_response.is_none() {
 
-                response.errors = Some(errors);
-                return Err(response)
-            }
+        info!("Step {next_step:?} requires response");
 
-            // SEND MESSAGE
-            let res = parsed_message.unwrap();
 
-            debug!("{:?}", serde_json::to_string(&res));
-            let res = send_message(res);
+        let uuid_step = Uuid::new_v4().to_string().replace("-", "");
 
-            if res.is_err() {
-                errors.push(String::from("Error sending message"));
+        let timestamp = match SystemTime::now().duration_since(UNIX_EPOCH) {
+            Ok(n) => n.as_millis().to_string(),
+            Err(_) => panic!("SystemTime before UNIX EPOCH!"),
+        };
 
-                response.errors = Some(errors);
-                return Err(response)
-            }
+        let mut new_step = TrackerStep{
+            tracker_id: String::from(&tracker.as_ref().unwrap().id),
+            timestamp: timestamp.clone(),
+            id: uuid_step,
+            status: (next_step as u16).to_string(),
+            value: "".to_string(),
+            attached_files: "".to_string(),
+            message_reference: String::from(&log.register_id.clone()),
+            origin: StepOrigin::System.value().to_string(),
+        };
 
-            // PUBLISH MESSAGE TO CHANNEL
+        info!("Executing {next_step:?} handler function");
+        let parsed_message: Result<MessageRequest, String> = execute_function(&mut new_step, next_step, &log, "").await;
 
-            let new_log = MessageLog{
-                timestamp: timestamp.clone(),
-                destination_systems: vec!["3".to_string()],
-                origin_system: "3".to_string(),
-                phone_number: log.phone_number.to_string(),
-                origin: "OUTGOING".to_string(),
-                register_id: String::from(&res.as_ref().unwrap().references[0].reference),
-            };
+        if parsed_message.is_err() {
+            // errors.push(parsed_message.unwrap_err());
 
+            response.errors = Some(errors);
+            return Err(response)
+        }
 
-            publish_message(&new_log, &log.phone_number);
+        // QUEUE MESSAGE, published to channel once sent
 
+        let new_log = MessageLog{
+            timestamp: timestamp.clone(),
+            destination_systems: vec!["3".to_string()],
+            origin_system: "3".to_string(),
+            phone_number: log.phone_number.to_string(),
+            origin: "OUTGOING".to_string(),
+            register_id: "".to_string(),
+        };
 
-            // Updated request status
+        let res = parsed_message.unwrap();
+        debug!("{:?}", serde_json::to_string(&res));
+        let outbox_entry = OutboxEntry::new(&new_step, res, Some(new_log));
 
-            let step_res = create_new_step(&new_step);
 
-            if step_res.is_err() {
-                errors.push(format!("Unable to create new step: {}", step_res.as_ref().unwrap_err()));
+        // Updated request status
 
-                response.errors = Some(errors);
-                return Err(response)
-            }
+        let step_res = create_step_with_outbox(&new_step, &[&outbox_entry]);
 
+        if step_res.is_err() {
+            errors.push(format!("Unable to create new step: {}", step_res.as_ref().unwrap_err()));
 
-            references.push(ModifiedReference{ system: "REDIS".to_string(), reference: step_res.as_ref().unwrap().clone() });
+            response.errors = Some(errors);
+            return Err(response)
         }
 
 
-    }else{
-        error!("Origin system not supported: {}", &log.origin_system);
-        errors.push(format!("Origin system not supported: {}", &log.origin_system));
-
-        response.errors = Some(errors);
-        return Err(response)
+        references.push(ModifiedReference{ system: "REDIS".to_string(), reference: step_res.as_ref().unwrap().clone() });
+        references.push(ModifiedReference{ system: "OUTBOX".to_string(), reference: outbox_entry.id.clone() });
     }
 
-
-
-
     response.errors = None;
     response.references = references;
     Ok(response)
@@ -283,6 +280,12 @@ pub async fn incoming_message(log: MessageLog) -> Result<StandardResponse, Stand
     let mut errors: Vec<String> = vec![];
     let mut references: Vec<ModifiedReference> = vec![];
 
+    // Message normalized by the channel the user talks through
+    let message = get_channel(&log.phone_number).get_message(&log.phone_number, &log.register_id);
+
+    let contact_name = message.as_ref().ok().and_then(|message| message.contact_name.clone());
+    let window_reopened = record_inbound(&log, contact_name.as_deref());
+
     // Get last tracker
     info!("Obtaining last tracker for phone number");
     debug!("Obtaining last tracker for phone number {}", &log.phone_number);
@@ -302,6 +305,14 @@ pub async fn incoming_message(log: MessageLog) -> Result<StandardResponse, Stand
         return Err(response)
     }
 
+    if tracker.as_ref().unwrap().state == TrackerState::Expired.value() {
+        error!("Last tracker for user {} expired", &log.phone_number);
+        errors.push(format!("No active request tracker found for user {}", &log.phone_number));
+
+        response.errors = Some(errors);
+        return Err(response)
+    }
+
     info!("Found tracker for phone number");
     debug!("Found tracker {} for phone number {}", tracker.as_ref().unwrap().id, &log.phone_number);
 
@@ -331,19 +342,50 @@ pub async fn incoming_message(log: MessageLog) -> Result<StandardResponse, Stand
     info!("Getting message content for step associated message reference");
     info!("Getting message content for step {} associated message reference {}", step.as_ref().unwrap().id, step.as_ref().unwrap().message_reference.replace("whatsapp-workflow:", ""));
 
-    let mut register_id = log.register_id.clone();
-
+    info!("register id: {}", log.register_id);
 
-    // Get message
-    info!("register id: {}", register_id);
-    let message: Result<Event, RedisError>= get_user_message(&register_id, &log.phone_number);
     if message.is_err() {
+        errors.push(message.unwrap_err());
 
-        // errors.push(message.unwrap_err().to_string());
         response.errors = Some(errors);
         return Err(response)
     }
 
+    let message = message.unwrap();
+
+    // Answer to the resume prompt sent when the user started a new request with this one unfinished
+    if tracker.as_ref().unwrap().resume_prompt_sent {
+        return resume_or_restart(&log, tracker.as_ref().unwrap(), step.as_ref().unwrap(), &message).await
+    }
+
+    // Conversation was handed off to an agent, messages are forwarded instead of running the flow
+    if tracker.as_ref().unwrap().state == TrackerState::Handoff.value() {
+        return forward_to_agent(&log, tracker.as_ref().unwrap(), &message)
+    }
+
+    // User asked to talk with an agent
+    if message.message_type == MessageType::PlainText && message.content.trim().to_lowercase() == AGENT_COMMAND {
+        info!("User requested an agent");
+        let handoff_res = start_handoff(tracker.as_ref().unwrap(), "user_request");
+
+        if handoff_res.is_err() {
+            errors.push(handoff_res.unwrap_err());
+
+            response.errors = Some(errors);
+            return Err(response)
+        }
+
+        response.errors = None;
+        response.references = handoff_res.unwrap();
+        return Ok(response)
+    }
+
+    // Quotes template sent while the window was closed has no list, it's sent once the customer writes back
+    if window_reopened && FlowStatus::get_from_value(&step.as_ref().unwrap().status) == FlowStatus::QuotesSent && message.message_type != MessageType::ListSelection {
+        info!("Customer service window reopened, sending quotes list again");
+        return continue_flow(&log, tracker.as_ref().unwrap(), step.as_ref().unwrap()).await
+    }
+
     info!("Found message content for specified reference");
     debug!("Found message content for specified reference {}", step.as_ref().unwrap().message_reference);
 
@@ -355,14 +397,15 @@ pub async fn incoming_message(log: MessageLog) -> Result<StandardResponse, Stand
     // Get possible next steps based on current status
     let status: FlowStatus = FlowStatus::get_from_value(&step.as_ref().unwrap().status);
 
-    if status.value().next_step.is_none() {
+    let next_step = get_next_step(tracker.as_ref().unwrap(), status);
+
+    if next_step.is_none() {
         errors.push("No possible next step".to_string());
         // implement a solution that doesnt throws an error when request is finished in last status
         response.errors = Some(errors);
         return Err(response)
     }
-    let next_step_status = status.value().next_step.unwrap() as u16;
-    let next_step = FlowStatus::get_from_value(&next_step_status.to_string());
+    let next_step = next_step.unwrap();
     info!("Next step found");
     debug!("Next step found: {:?}", &next_step);
 
@@ -379,13 +422,14 @@ pub async fn incoming_message(log: MessageLog) -> Result<StandardResponse, Stand
     info!("Next step requires user response");
 
     // Filter step based if message type fits required response type(plain text, plain text with image, list selection, button selection)
-    let message_type = find_message_type(message.as_ref().unwrap());
+    let message_type = message.message_type;
 
     info!("Message type expected found");
     debug!("Message type expected found: {:?}", &message_type);
 
-    if next_step.value().required_response.is_some() && next_step.value().required_response.unwrap() != message_type {
+    if next_step.value().required_response.is_some() && !next_step.value().required_response.unwrap().contains(&message_type) {
         errors.push(format!("Message type {:?} doesnt match with the next step required message type {:?}", message_type, next_step));
+        register_failed_attempt(tracker.as_ref().unwrap());
 
         // TODO: implement a solution that doesnt throws an error when request is finished in last status
         response.errors = Some(errors);
@@ -394,7 +438,7 @@ pub async fn incoming_message(log: MessageLog) -> Result<StandardResponse, Stand
 
     // Obtaining message content
     info!("Obtaining content from message reference");
-    let message_content = get_message_content(message.as_ref().unwrap());
+    let message_content = message.content.clone();
 
     // Filter based on regex(if defined)
     info!("Proceeding to evaluate regex");
@@ -411,6 +455,7 @@ pub async fn incoming_message(log: MessageLog) -> Result<StandardResponse, Stand
         if caps.is_none() {
             error!("Message content doesnt match required regex");
             errors.push("Message content doesnt match required regex".to_string());
+            register_failed_attempt(tracker.as_ref().unwrap());
 
             response.errors = Some(errors);
             return Err(response)
@@ -436,6 +481,7 @@ pub async fn incoming_message(log: MessageLog) -> Result<StandardResponse, Stand
         value: message_content.clone(),
         attached_files: "".to_string(),
         message_reference: String::from(&log.register_id.clone()),
+        origin: StepOrigin::User.value().to_string(),
     };
 
 
@@ -443,26 +489,36 @@ pub async fn incoming_message(log: MessageLog) -> Result<StandardResponse, Stand
     // Execute handler function
     let parsed_message: Result<MessageRequest, String> = execute_function(&mut new_step, next_step, &log, message_content.as_str()).await;
     if parsed_message.is_err() {
+        register_failed_attempt(tracker.as_ref().unwrap());
 
         response.errors = Some(errors);
         return Err(response)
     }
 
-    // SEND MESSAGE
+    // QUEUE MESSAGE
     let res = parsed_message.unwrap();
-
     info!("{:?}", serde_json::to_string(&res));
-    let res = send_message(res);
 
-    if res.is_err() {
-        errors.push(String::from("Error sending message"));
+    info!("Next step status: {}, step: {}", new_step.status , step.as_ref().unwrap().status);
 
-        response.errors = Some(errors);
-        return Err(response)
-    }
+    // Publish to channel once sent only if current status is different to computed next status
+    let new_log = if new_step.status != step.as_ref().unwrap().status {
+        Some(MessageLog{
+            timestamp: timestamp.clone(),
+            destination_systems: vec!["3".to_string()],
+            origin_system: "3".to_string(),
+            phone_number: log.phone_number.to_string(),
+            origin: "OUTGOING".to_string(),
+            register_id: "".to_string(),
+        })
+    } else {
+        None
+    };
+
+    let outbox_entry = OutboxEntry::new(&new_step, res, new_log);
 
     // Updated request status
-    let step_res = create_new_step(&new_step);
+    let step_res = create_step_with_outbox(&new_step, &[&outbox_entry]);
 
     if step_res.is_err() {
         errors.push(format!("Unable to create new step: {}", step_res.as_ref().unwrap_err()));
@@ -472,60 +528,1527 @@ pub async fn incoming_message(log: MessageLog) -> Result<StandardResponse, Stand
     }
 
     references.push(ModifiedReference{ system: "REDIS".to_string(), reference: step_res.as_ref().unwrap().clone() });
+    references.push(ModifiedReference{ system: "OUTBOX".to_string(), reference: outbox_entry.id.clone() });
 
 
-    info!("Next step status: {}, step: {}", new_step.status , step.as_ref().unwrap().status);
-    if new_step.status != step.as_ref().unwrap().status {
+    response.errors = None;
+    response.references = references;
+    Ok(response)
+}
 
-        // Update if current status is different to computed next status
-        // PUBLISH MESSAGE TO CHANNEL
-        let new_log = MessageLog{
-            timestamp: timestamp.clone(),
-            destination_systems: vec!["3".to_string()],
-            origin_system: "3".to_string(),
-            phone_number: log.phone_number.to_string(),
-            origin: "OUTGOING".to_string(),
-            register_id: String::from(&res.as_ref().unwrap().references[0].reference),
-        };
+// Creates a new tracker for the user along with its initial step
+fn start_new_tracker(log: &MessageLog) -> Result<Vec<ModifiedReference>, String> {
+    let mut references = vec![];
 
+    // Create new workflow
+    // Removes hyphen for limitation on query syntax
+    let uuid_tracker = Uuid::new_v4().to_string().replace("-", "");
 
-        publish_message(&new_log, &log.phone_number);
+    let created = create_new_tracker(&uuid_tracker, &log.phone_number);
 
+    if created.is_err() {
+        error!("Error creating tracker for request");
+        return Err("Error creating tracker for request".to_string())
     }
+    references.push(ModifiedReference {
+        system: "REDIS".to_string(),
+        reference: created.unwrap().to_string(),
+    });
 
+    let timestamp = match SystemTime::now().duration_since(UNIX_EPOCH) {
+        Ok(n) => n.as_millis().to_string(),
+        Err(_) => panic!("SystemTime before UNIX EPOCH!"),
+    };
 
-    response.errors = None;
-    response.references = references;
-    Ok(response)
+    // Tracker exists even if the event can't be published, so the failure is only logged
+    let started_log = MessageLog{
+        timestamp: timestamp.clone(),
+        destination_systems: vec![SYSTEM_ID.to_string()],
+        origin_system: SYSTEM_ID.to_string(),
+        phone_number: log.phone_number.to_string(),
+        origin: "OUTGOING".to_string(),
+        register_id: String::from(&log.register_id),
+    };
+
+    let publish_res = publish_message(&started_log, &log.phone_number, &WorkflowEvent::new("TrackerStarted", &uuid_tracker, ""));
+
+    if publish_res.is_err() {
+        error!("Error publishing tracker start {}", publish_res.unwrap_err());
+    }
+
+    let res = increment_customer_requests(&log.phone_number);
+
+    if res.is_err() {
+        error!("Error counting customer request: {}", res.unwrap_err());
+    }
+
+    // Create initial tracker step
+    let uuid_step = Uuid::new_v4().to_string().replace("-", "");
+    let initial_step = TrackerStep{
+        tracker_id: uuid_tracker.clone(),
+        timestamp: timestamp.clone(), // It's set on creation function
+        id: uuid_step,
+        status: (FlowStatus::FlowStarted as u16).to_string(),
+        value: "".to_string(),
+        attached_files: "".to_string(),
+        message_reference: String::from(&log.register_id),
+        origin: StepOrigin::System.value().to_string(),
+    };
+
+    // Create new step register
+    let step_res = create_new_step(&initial_step);
+
+
+    if step_res.is_err() {
+        error!("Error creating initial request for tracker");
+        return Err("Error creating initial request for tracker".to_string())
+    }
+    references.push(ModifiedReference {
+        system: "REDIS".to_string(),
+        reference: step_res.unwrap().to_string(),
+    });
+
+    Ok(references)
+}
+
+// Returns the user last tracker if it was left in the middle of the flow
+fn get_unfinished_tracker(phone_number: &str) -> Option<RequestTracker> {
+    let tracker = get_last_tracker(phone_number);
+
+    if tracker.is_err() || tracker.as_ref().unwrap().state != TrackerState::Active.value() {
+        return None
+    }
+
+    // Trackers created before states were introduced are only finished by their last step
+    let step = get_last_tracker_step(&tracker.as_ref().unwrap().id);
+
+    if step.is_err() || FlowStatus::get_from_value(&step.unwrap().status) == FlowStatus::RequestAccepted {
+        return None
+    }
+
+    Some(tracker.unwrap())
 }
 
-pub fn get_tracker_steps(tracker_id: &str) -> Result<Vec<TrackerStep>, StandardResponse> {
+fn send_resume_prompt(tracker: &RequestTracker) -> Result<(), String> {
+    let resume_message = MessageRequest{
+        system_id: SYSTEM_ID,
+        to: vec![tracker.phone_number.clone()],
+        message_type: "button".to_string(),
+        content: MessageContent {
+            body: Some("Tienes una solicitud de repuesto en curso, ¿deseas continuar donde quedaste o comenzar una nueva?".to_string()),
+            list: None,
+            buttons: Some(ButtonMessage{
+                title: "Solicitud en curso".to_string(),
+                choices: vec![
+                    Choice{ id: "resume-id".to_string(), value: "Continuar".to_string() },
+                    Choice{ id: "restart-id".to_string(), value: "Comenzar de nuevo".to_string() },
+                ],
+            }),
+        },
+    };
+
+    let res = queue_message(&tracker.id, resume_message, None);
+
+    if res.is_err() {
+        return Err(res.unwrap_err())
+    }
+
+    set_tracker_field(&tracker.id, "resume_prompt_sent", "1")
+}
 
+// Handles the user answer to the resume prompt, continuing the unfinished tracker or starting over
+async fn resume_or_restart(log: &MessageLog, tracker: &RequestTracker, step: &TrackerStep, message: &InboundMessage) -> Result<StandardResponse, StandardResponse> {
     let mut response: StandardResponse = StandardResponse::new();
     let mut errors: Vec<String> = vec![];
+    let mut references: Vec<ModifiedReference> = vec![];
+
+    let message_content = message.content.clone();
+
+    // Any other reply gets the prompt again, so the user isn't left without an answer
+    if message.message_type != MessageType::ButtonSelection || (message_content != "resume-id" && message_content != "restart-id") {
+        info!("Expected resume or restart selection, sending resume prompt again");
+        let prompt_res = send_resume_prompt(tracker);
+
+        if prompt_res.is_err() {
+            errors.push(format!("Error sending resume prompt {}", prompt_res.unwrap_err()));
 
-    // Obtain redis bulk for tracker steps corresponding to tracker id
-    info!("Obtaining tracker {tracker_id} steps");
-    let value = get_all_tracker_steps(tracker_id);
+            response.errors = Some(errors);
+            return Err(response)
+        }
+
+        response.errors = None;
+        response.references = references;
+        return Ok(response)
+    }
+
+    let res = set_tracker_field(&tracker.id, "resume_prompt_sent", "0");
+
+    if res.is_err() {
+        errors.push(res.unwrap_err());
 
-    if value.is_err() {
-        error!("Error retrieving tracker steps {}", value.as_ref().unwrap_err());
-        errors.push(value.unwrap_err());
         response.errors = Some(errors);
         return Err(response)
     }
 
-    let mut values:Vec<TrackerStep> = vec![];
+    if message_content == "restart-id" {
+        info!("Restarting request, abandoning tracker {}", &tracker.id);
+
+        let res = set_tracker_state(&tracker.id, TrackerState::Abandoned);
 
-    info!("Parsing found bulks into struct");
-    // Parse redis bulk to tracker step struct
-    for x in value.unwrap().as_sequence().unwrap(){
-        if let Bulk(register) = x {
-            let tracker_step = TrackerStep::default()
-                .parse_from_redis(register);
-            values.push(tracker_step);
+        if res.is_err() {
+            errors.push(res.unwrap_err());
+
+            response.errors = Some(errors);
+            return Err(response)
         }
-    }
 
-    Ok(values)
-}
\ No newline at end of file
+        let created = start_new_tracker(log);
+
+        if created.is_err() {
+            errors.push(created.unwrap_err());
+
+            response.errors = Some(errors);
+            return Err(response)
+        }
+        let created = created.unwrap();
+        let tracker_id = created[0].reference.replace("whatsapp-request:", "");
+        references.extend(created);
+
+        let mut message_request = FlowStatus::FlowStarted.value().successful_response.unwrap();
+        message_request.to.push(log.phone_number.clone());
+
+        let res = queue_message(&tracker_id, message_request, None);
+
+        if res.is_err() {
+            errors.push(format!("Error queueing message {}", res.unwrap_err()));
+
+            response.errors = Some(errors);
+            return Err(response)
+        }
+        references.push(ModifiedReference{ system: "OUTBOX".to_string(), reference: res.unwrap() });
+
+        response.errors = None;
+        response.references = references;
+        return Ok(response)
+    }
+
+    info!("Resuming tracker {} from status {}", &tracker.id, &step.status);
+    continue_flow(log, tracker, step).await
+}
+
+// Continues the flow from the specified step, either executing the pending system step or prompting the user again
+async fn continue_flow(log: &MessageLog, tracker: &RequestTracker, step: &TrackerStep) -> Result<StandardResponse, StandardResponse> {
+    let mut response: StandardResponse = StandardResponse::new();
+    let mut errors: Vec<String> = vec![];
+
+    let status = FlowStatus::get_from_value(&step.status);
+    let next_step = get_next_step(tracker, status);
+
+    // Pending system step, continue the flow as if it came from this system
+    if next_step.is_some() && next_step.unwrap().value().required_response.is_none() {
+        let mut system_log = log.clone();
+        system_log.origin_system = SYSTEM_ID.to_string();
+
+        return advance_flow(&system_log, &tracker.id).await
+    }
+
+    // Send again the message the user is expected to answer
+    let prompt = match get_step_prompt(step, log) {
+        Ok(prompt) => prompt,
+        Err(err) => {
+            errors.push(err);
+
+            response.errors = Some(errors);
+            return Err(response)
+        }
+    };
+
+    let res = queue_message(&tracker.id, prompt, None);
+
+    if res.is_err() {
+        errors.push(format!("Error queueing message {}", res.unwrap_err()));
+
+        response.errors = Some(errors);
+        return Err(response)
+    }
+
+    response.errors = None;
+    response.references = vec![ModifiedReference{ system: "OUTBOX".to_string(), reference: res.unwrap() }];
+    Ok(response)
+}
+
+// Counts a failed attempt to answer the current step, handing off the tracker to an agent once retries are exhausted
+fn register_failed_attempt(tracker: &RequestTracker) {
+    let attempts = increment_tracker_field(&tracker.id, "failed_attempts");
+
+    if attempts.is_err() {
+        error!("Error registering failed attempt: {}", attempts.unwrap_err());
+        return
+    }
+
+    let max_attempts = get_env_setting("MAX_FAILED_ATTEMPTS", 3) as i64;
+
+    if attempts.unwrap() >= max_attempts {
+        info!("Retries exhausted for tracker {}, handing off to an agent", &tracker.id);
+        let handoff_res = start_handoff(tracker, "retries_exhausted");
+
+        if handoff_res.is_err() {
+            error!("Error handing off tracker {}: {}", &tracker.id, handoff_res.unwrap_err());
+        }
+    }
+}
+
+// Pauses the flow for the tracker and notifies agents the customer requires attention
+fn start_handoff(tracker: &RequestTracker, reason: &str) -> Result<Vec<ModifiedReference>, String> {
+    let mut references: Vec<ModifiedReference> = vec![];
+
+    let res = set_tracker_state(&tracker.id, TrackerState::Handoff);
+
+    if res.is_err() {
+        return Err(res.unwrap_err())
+    }
+
+    let timestamp = match SystemTime::now().duration_since(UNIX_EPOCH) {
+        Ok(n) => n.as_millis().to_string(),
+        Err(_) => panic!("SystemTime before UNIX EPOCH!"),
+    };
+
+    let handoff_step = TrackerStep{
+        tracker_id: String::from(&tracker.id),
+        timestamp: timestamp.clone(),
+        id: Uuid::new_v4().to_string().replace("-", ""),
+        status: (FlowStatus::AgentHandoff as u16).to_string(),
+        value: reason.to_string(),
+        attached_files: "".to_string(),
+        message_reference: "".to_string(),
+        origin: StepOrigin::System.value().to_string(),
+    };
+
+    let mut message_request = FlowStatus::AgentHandoff.value().successful_response.unwrap();
+    message_request.to.push(tracker.phone_number.clone());
+
+    let outbox_entry = OutboxEntry::new(&handoff_step, message_request, None);
+    let step_res = create_step_with_outbox(&handoff_step, &[&outbox_entry]);
+
+    if step_res.is_err() {
+        return Err(format!("Unable to create new step: {}", step_res.unwrap_err()))
+    }
+    references.push(ModifiedReference{ system: "REDIS".to_string(), reference: step_res.unwrap() });
+    references.push(ModifiedReference{ system: "OUTBOX".to_string(), reference: outbox_entry.id.clone() });
+
+    let notification = AgentNotification{
+        tracker_id: tracker.id.clone(),
+        phone_number: tracker.phone_number.clone(),
+        timestamp,
+        event: "HANDOFF_REQUESTED".to_string(),
+        content: reason.to_string(),
+    };
+
+    let publish_res = publish_agent_notification(&notification);
+
+    if publish_res.is_err() {
+        return Err(format!("Error publishing agent notification {}", publish_res.unwrap_err()))
+    }
+
+    Ok(references)
+}
+
+// Records the user message as part of the handoff conversation and sends it to the agents channel
+fn forward_to_agent(log: &MessageLog, tracker: &RequestTracker, message: &InboundMessage) -> Result<StandardResponse, StandardResponse> {
+    let mut response: StandardResponse = StandardResponse::new();
+    let mut errors: Vec<String> = vec![];
+
+    info!("Forwarding message from tracker {} to agents", &tracker.id);
+    let message_content = message.content.clone();
+
+    let timestamp = match SystemTime::now().duration_since(UNIX_EPOCH) {
+        Ok(n) => n.as_millis().to_string(),
+        Err(_) => panic!("SystemTime before UNIX EPOCH!"),
+    };
+
+    let handoff_step = TrackerStep{
+        tracker_id: String::from(&tracker.id),
+        timestamp: timestamp.clone(),
+        id: Uuid::new_v4().to_string().replace("-", ""),
+        status: (FlowStatus::AgentHandoff as u16).to_string(),
+        value: message_content.clone(),
+        attached_files: "".to_string(),
+        message_reference: String::from(&log.register_id),
+        origin: StepOrigin::User.value().to_string(),
+    };
+
+    let step_res = create_new_step(&handoff_step);
+
+    if step_res.is_err() {
+        errors.push(format!("Unable to create new step: {}", step_res.as_ref().unwrap_err()));
+
+        response.errors = Some(errors);
+        return Err(response)
+    }
+
+    let notification = AgentNotification{
+        tracker_id: tracker.id.clone(),
+        phone_number: tracker.phone_number.clone(),
+        timestamp,
+        event: "USER_MESSAGE".to_string(),
+        content: message_content,
+    };
+
+    let publish_res = publish_agent_notification(&notification);
+
+    if publish_res.is_err() {
+        errors.push(format!("Error publishing agent notification {}", publish_res.as_ref().unwrap_err()));
+
+        response.errors = Some(errors);
+        return Err(response)
+    }
+
+    response.errors = None;
+    response.references = vec![ModifiedReference{ system: "REDIS".to_string(), reference: step_res.unwrap() }];
+    Ok(response)
+}
+
+pub fn request_handoff(tracker_id: &str) -> Result<StandardResponse, StandardResponse> {
+    let mut response: StandardResponse = StandardResponse::new();
+    let mut errors: Vec<String> = vec![];
+
+    let tracker = get_tracker(tracker_id);
+
+    if tracker.is_err() {
+        return Err(tracker_error(tracker_id, tracker.unwrap_err()))
+    }
+
+    info!("Handoff requested for tracker {}", tracker_id);
+    let handoff_res = start_handoff(tracker.as_ref().unwrap(), "agent_request");
+
+    if handoff_res.is_err() {
+        errors.push(handoff_res.unwrap_err());
+
+        response.errors = Some(errors);
+        return Err(response)
+    }
+
+    response.references = handoff_res.unwrap();
+    Ok(response)
+}
+
+// Sends an agent message to the tracker phone, taking over the conversation if it wasn't handed off yet
+pub fn agent_reply(tracker_id: &str, reply: &AgentReply) -> Result<StandardResponse, StandardResponse> {
+    let mut response: StandardResponse = StandardResponse::new();
+    let mut errors: Vec<String> = vec![];
+    let mut references: Vec<ModifiedReference> = vec![];
+
+    if reply.message.is_none() && reply.media_url.is_none() {
+        errors.push("Either message or media_url must be provided".to_string());
+
+        response.errors = Some(errors);
+        return Err(response)
+    }
+
+    let media_type = reply.media_type.clone().unwrap_or("image".to_string());
+
+    if !AGENT_MEDIA_TYPES.contains(&media_type.as_str()) {
+        errors.push(format!("Media type {} is not supported, expected one of {}", media_type, AGENT_MEDIA_TYPES.join(", ")));
+
+        response.errors = Some(errors);
+        return Err(response)
+    }
+
+    let tracker = get_tracker(tracker_id);
+
+    if tracker.is_err() {
+        return Err(tracker_error(tracker_id, tracker.unwrap_err()))
+    }
+
+    if tracker.as_ref().unwrap().state != TrackerState::Handoff.value() {
+        info!("Agent taking over tracker {}", tracker_id);
+        let handoff_res = start_handoff(tracker.as_ref().unwrap(), "agent_takeover");
+
+        if handoff_res.is_err() {
+            errors.push(handoff_res.unwrap_err());
+
+            response.errors = Some(errors);
+            return Err(response)
+        }
+        references.extend(handoff_res.unwrap());
+    }
+
+    let phone_number = tracker.as_ref().unwrap().phone_number.clone();
+
+    // Text goes before the media, since media messages only carry the media link
+    let mut reply_messages: Vec<MessageRequest> = vec![];
+    if reply.message.is_some() {
+        reply_messages.push(MessageRequest{
+            system_id: SYSTEM_ID,
+            to: vec![phone_number.clone()],
+            message_type: "text".to_string(),
+            content: MessageContent {
+                body: reply.message.clone(),
+                list: None,
+                buttons: None,
+            },
+        });
+    }
+
+    if reply.media_url.is_some() {
+        reply_messages.push(MessageRequest{
+            system_id: SYSTEM_ID,
+            to: vec![phone_number.clone()],
+            message_type: media_type,
+            content: MessageContent {
+                body: reply.media_url.clone(),
+                list: None,
+                buttons: None,
+            },
+        });
+    }
+
+    let timestamp = match SystemTime::now().duration_since(UNIX_EPOCH) {
+        Ok(n) => n.as_millis().to_string(),
+        Err(_) => panic!("SystemTime before UNIX EPOCH!"),
+    };
+
+    let agent_step = TrackerStep{
+        tracker_id: tracker_id.to_string(),
+        timestamp: timestamp.clone(),
+        id: Uuid::new_v4().to_string().replace("-", ""),
+        status: (FlowStatus::AgentHandoff as u16).to_string(),
+        value: reply.message.clone().unwrap_or_default(),
+        attached_files: reply.media_url.clone().unwrap_or_default(),
+        message_reference: "".to_string(),
+        origin: StepOrigin::Agent.value().to_string(),
+    };
+
+    // Messages are queued along with the step, so they're all recorded and delivered in order
+    let outbox_entries: Vec<OutboxEntry> = reply_messages.into_iter()
+        .map(|reply_message| OutboxEntry::new(&agent_step, reply_message, None))
+        .collect();
+
+    let step_res = create_step_with_outbox(&agent_step, &outbox_entries.iter().collect::<Vec<&OutboxEntry>>());
+
+    if step_res.is_err() {
+        errors.push(format!("Unable to create new step: {}", step_res.as_ref().unwrap_err()));
+
+        response.errors = Some(errors);
+        return Err(response)
+    }
+    references.push(ModifiedReference{ system: "REDIS".to_string(), reference: step_res.unwrap() });
+
+    let entry_ids = outbox_entries.iter().map(|entry| entry.id.clone()).collect::<Vec<String>>();
+    references.extend(entry_ids.iter().map(|entry_id| ModifiedReference{ system: "OUTBOX".to_string(), reference: entry_id.clone() }));
+
+    // Sent to whatsapp-manager, publishing it to this system would trigger the flow
+    let agent_log = MessageLog{
+        timestamp,
+        destination_systems: vec!["1".to_string()],
+        origin_system: SYSTEM_ID.to_string(),
+        phone_number: phone_number.clone(),
+        origin: "AGENT".to_string(),
+        register_id: entry_ids.join(","),
+    };
+
+    let publish_res = publish_message(&agent_log, &phone_number, &WorkflowEvent::new("AgentReply", tracker_id, &agent_step.id));
+
+    if publish_res.is_err() {
+        errors.push(format!("Error publishing message {}", publish_res.as_ref().unwrap_err()));
+    }
+
+    response.references = references;
+
+    if !errors.is_empty() {
+        response.errors = Some(errors);
+        return Err(response)
+    }
+
+    Ok(response)
+}
+
+// Hands the conversation back to the bot, continuing the flow from the specified status
+pub async fn release_handoff(tracker_id: &str, release: &HandoffRelease) -> Result<StandardResponse, StandardResponse> {
+    let mut response: StandardResponse = StandardResponse::new();
+    let mut errors: Vec<String> = vec![];
+
+    let tracker = get_tracker(tracker_id);
+
+    if tracker.is_err() {
+        return Err(tracker_error(tracker_id, tracker.unwrap_err()))
+    }
+
+    if tracker.as_ref().unwrap().state != TrackerState::Handoff.value() {
+        errors.push(format!("Tracker {} is not handed off to an agent", tracker_id));
+
+        response.errors = Some(errors);
+        return Err(response)
+    }
+
+    let status = all::<FlowStatus>().find(|status| *status as u16 == release.status);
+
+    if status.is_none() || status == Some(FlowStatus::AgentHandoff) {
+        errors.push(format!("Status {} is not a valid flow status", release.status));
+
+        response.errors = Some(errors);
+        return Err(response)
+    }
+
+    let tracker = tracker.unwrap();
+    let status = status.unwrap();
+
+    let timestamp = match SystemTime::now().duration_since(UNIX_EPOCH) {
+        Ok(n) => n.as_millis().to_string(),
+        Err(_) => panic!("SystemTime before UNIX EPOCH!"),
+    };
+
+    let release_step = TrackerStep{
+        tracker_id: tracker_id.to_string(),
+        timestamp: timestamp.clone(),
+        id: Uuid::new_v4().to_string().replace("-", ""),
+        status: release.status.to_string(),
+        value: "".to_string(),
+        attached_files: "".to_string(),
+        message_reference: "".to_string(),
+        origin: StepOrigin::Agent.value().to_string(),
+    };
+
+    let log = MessageLog{
+        timestamp,
+        destination_systems: vec![SYSTEM_ID.to_string()],
+        origin_system: SYSTEM_ID.to_string(),
+        phone_number: tracker.phone_number.clone(),
+        origin: "OUTGOING".to_string(),
+        register_id: "".to_string(),
+    };
+
+    // Pending system step is executed once released, otherwise the message the user is expected to answer is sent again
+    let next_step = get_next_step(&tracker, status);
+    let continues = next_step.is_some() && next_step.unwrap().value().required_response.is_none();
+
+    let outbox_entry = if continues {
+        None
+    } else {
+        match get_step_prompt(&release_step, &log) {
+            Ok(prompt) => Some(OutboxEntry::new(&release_step, prompt, Some(log.clone()))),
+            Err(err) => {
+                errors.push(format!("Tracker can't be released at status {:?}: {}", status, err));
+
+                response.errors = Some(errors);
+                return Err(response)
+            }
+        }
+    };
+
+    info!("Releasing tracker {} at status {:?}", tracker_id, status);
+    let res = set_tracker_state(tracker_id, TrackerState::Active);
+
+    if res.is_err() {
+        errors.push(res.unwrap_err());
+
+        response.errors = Some(errors);
+        return Err(response)
+    }
+
+    let step_res = create_step_with_outbox(&release_step, &outbox_entry.iter().collect::<Vec<&OutboxEntry>>());
+
+    if step_res.is_err() {
+        errors.push(format!("Unable to create new step: {}", step_res.as_ref().unwrap_err()));
+
+        response.errors = Some(errors);
+        return Err(response)
+    }
+
+    response.references = vec![ModifiedReference{ system: "REDIS".to_string(), reference: step_res.unwrap() }];
+
+    if outbox_entry.is_some() {
+        response.references.push(ModifiedReference{ system: "OUTBOX".to_string(), reference: outbox_entry.unwrap().id });
+        return Ok(response)
+    }
+
+    let continue_res = advance_flow(&log, tracker_id).await;
+
+    if continue_res.is_err() {
+        return continue_res
+    }
+
+    response.references.extend(continue_res.unwrap().references);
+    Ok(response)
+}
+
+// Records the result of the classification system and lets the customer know which part was identified
+pub async fn classify_tracker(tracker_id: &str, classification: &PartClassification) -> Result<StandardResponse, StandardResponse> {
+    let mut response: StandardResponse = StandardResponse::new();
+    let mut errors: Vec<String> = vec![];
+
+    let tracker = get_tracker(tracker_id);
+
+    if tracker.is_err() {
+        return Err(tracker_error(tracker_id, tracker.unwrap_err()))
+    }
+
+    // Trackers without steps don't have a status yet
+    let last_status = &tracker.as_ref().unwrap().status;
+
+    if last_status.is_empty() || ![FlowStatus::RequestAccepted, FlowStatus::PartClassified].contains(&FlowStatus::get_from_value(last_status)) {
+        errors.push(format!("Tracker {} request hasn't been accepted", tracker_id));
+
+        response.errors = Some(errors);
+        response.conflict = true;
+        return Err(response)
+    }
+
+    let timestamp = match SystemTime::now().duration_since(UNIX_EPOCH) {
+        Ok(n) => n.as_millis().to_string(),
+        Err(_) => panic!("SystemTime before UNIX EPOCH!"),
+    };
+
+    let classification_value = serde_json::to_string(classification).unwrap();
+    let mut classified_step = TrackerStep{
+        tracker_id: tracker_id.to_string(),
+        timestamp: timestamp.clone(),
+        id: Uuid::new_v4().to_string().replace("-", ""),
+        status: (FlowStatus::PartClassified as u16).to_string(),
+        value: classification_value.clone(),
+        attached_files: "".to_string(),
+        message_reference: "".to_string(),
+        origin: StepOrigin::System.value().to_string(),
+    };
+
+    let log = MessageLog{
+        timestamp,
+        destination_systems: vec![SYSTEM_ID.to_string()],
+        origin_system: PART_CLASSIFICATION_SYSTEM_ID.to_string(),
+        phone_number: tracker.as_ref().unwrap().phone_number.clone(),
+        origin: "CLASSIFICATION".to_string(),
+        register_id: "".to_string(),
+    };
+
+    info!("Part classified as {} for tracker {}", &classification.category, tracker_id);
+    let message = execute_function(&mut classified_step, FlowStatus::PartClassified, &log, &classification_value).await;
+
+    if let Err(err) = &message {
+        errors.push(err.clone());
+
+        response.errors = Some(errors);
+        return Err(response)
+    }
+
+    // Replaced by the step template if the customer service window is closed when it's delivered
+    let outbox_entry = OutboxEntry::new(&classified_step, message.unwrap(), None);
+    let step_res = create_step_with_outbox(&classified_step, &[&outbox_entry]);
+
+    if step_res.is_err() {
+        errors.push(format!("Unable to create new step: {}", step_res.as_ref().unwrap_err()));
+
+        response.errors = Some(errors);
+        return Err(response)
+    }
+
+    response.references.push(ModifiedReference{ system: "REDIS".to_string(), reference: step_res.unwrap() });
+    response.references.push(ModifiedReference{ system: "OUTBOX".to_string(), reference: outbox_entry.id });
+    Ok(response)
+}
+
+// Attaches suppliers quotes to an accepted request and offers them to the customer
+pub async fn add_quotes(tracker_id: &str, quotes_request: &QuotesRequest) -> Result<StandardResponse, StandardResponse> {
+    let mut response: StandardResponse = StandardResponse::new();
+    let mut errors: Vec<String> = vec![];
+
+    let tracker = get_tracker(tracker_id);
+
+    if tracker.is_err() {
+        return Err(tracker_error(tracker_id, tracker.unwrap_err()))
+    }
+
+    // Trackers without steps don't have a status yet
+    let last_status = &tracker.as_ref().unwrap().status;
+
+    if last_status.is_empty() || ![FlowStatus::RequestAccepted, FlowStatus::PartClassified, FlowStatus::QuotesSent].contains(&FlowStatus::get_from_value(last_status)) {
+        errors.push(format!("Tracker {} can't receive quotes at status {}", tracker_id, last_status));
+
+        response.errors = Some(errors);
+        response.conflict = true;
+        return Err(response)
+    }
+
+    if quotes_request.quotes.is_empty() {
+        errors.push("No quotes provided".to_string());
+
+        response.errors = Some(errors);
+        return Err(response)
+    }
+
+    let phone_number = tracker.as_ref().unwrap().phone_number.clone();
+    let mut photo_messages: Vec<MessageRequest> = vec![];
+    for quote in &quotes_request.quotes {
+        let mut quote = quote.clone();
+        quote.id = Uuid::new_v4().to_string();
+
+        let res = save_quote(tracker_id, &quote);
+
+        if res.is_err() {
+            errors.push(res.unwrap_err());
+
+            response.errors = Some(errors);
+            return Err(response)
+        }
+
+        if quote.photo_url.is_some() {
+            photo_messages.push(MessageRequest{
+                system_id: SYSTEM_ID,
+                to: vec![phone_number.clone()],
+                message_type: "image".to_string(),
+                content: MessageContent {
+                    body: quote.photo_url.clone(),
+                    list: None,
+                    buttons: None,
+                },
+            });
+        }
+    }
+
+    let timestamp = match SystemTime::now().duration_since(UNIX_EPOCH) {
+        Ok(n) => n.as_millis().to_string(),
+        Err(_) => panic!("SystemTime before UNIX EPOCH!"),
+    };
+
+    let mut quotes_step = TrackerStep{
+        tracker_id: tracker_id.to_string(),
+        timestamp: timestamp.clone(),
+        id: Uuid::new_v4().to_string().replace("-", ""),
+        status: (FlowStatus::QuotesSent as u16).to_string(),
+        value: "".to_string(),
+        attached_files: "".to_string(),
+        message_reference: "".to_string(),
+        origin: StepOrigin::System.value().to_string(),
+    };
+
+    let log = MessageLog{
+        timestamp,
+        destination_systems: vec![SYSTEM_ID.to_string()],
+        origin_system: SUPPLIER_SYSTEM_ID.to_string(),
+        phone_number: phone_number.clone(),
+        origin: "QUOTES".to_string(),
+        register_id: "".to_string(),
+    };
+
+    info!("Sending {} quotes to tracker {}", quotes_request.quotes.len(), tracker_id);
+    let message = execute_function(&mut quotes_step, FlowStatus::QuotesSent, &log, "").await;
+
+    if let Err(err) = &message {
+        errors.push(err.clone());
+
+        response.errors = Some(errors);
+        return Err(response)
+    }
+
+    // Customer answers the list with this system, not the mode selection
+    let res = set_user_mode(&phone_number, SYSTEM_ID);
+
+    if res.is_err() {
+        errors.push(res.unwrap_err());
+
+        response.errors = Some(errors);
+        return Err(response)
+    }
+
+    // Photos can't be sent outside the customer service window, the list is replaced by the step template
+    if !is_within_service_window(&phone_number) {
+        photo_messages.clear();
+    }
+
+    let mut outbox_entries: Vec<OutboxEntry> = photo_messages.into_iter()
+        .map(|photo_message| {
+            let mut entry = OutboxEntry::new(&quotes_step, photo_message, None);
+            entry.template = None;
+            entry
+        })
+        .collect();
+    outbox_entries.push(OutboxEntry::new(&quotes_step, message.unwrap(), None));
+
+    let step_res = create_step_with_outbox(&quotes_step, &outbox_entries.iter().collect::<Vec<&OutboxEntry>>());
+
+    if step_res.is_err() {
+        errors.push(format!("Unable to create new step: {}", step_res.as_ref().unwrap_err()));
+
+        response.errors = Some(errors);
+        return Err(response)
+    }
+
+    response.references.push(ModifiedReference{ system: "REDIS".to_string(), reference: step_res.unwrap() });
+    response.references.extend(outbox_entries.into_iter().map(|entry| ModifiedReference{ system: "OUTBOX".to_string(), reference: entry.id }));
+
+    Ok(response)
+}
+
+pub fn get_dead_letters(params: &OutboxParams) -> Result<OutboxPage, StandardResponse> {
+    let mut response: StandardResponse = StandardResponse::new();
+    let mut errors: Vec<String> = vec![];
+
+    let limit = params.limit.unwrap_or(20).min(100);
+    let offset = match &params.cursor {
+        Some(cursor) => match cursor.parse::<usize>() {
+            Ok(offset) => offset,
+            Err(_) => {
+                errors.push(format!("Invalid cursor {}", cursor));
+
+                response.errors = Some(errors);
+                return Err(response)
+            }
+        },
+        None => 0,
+    };
+
+    let dead_letters = get_dead_letter_ids(offset, limit);
+
+    if dead_letters.is_err() {
+        errors.push(dead_letters.unwrap_err());
+
+        response.errors = Some(errors);
+        return Err(response)
+    }
+
+    let (total, entry_ids) = dead_letters.unwrap();
+
+    let mut entries: Vec<OutboxEntry> = vec![];
+    for entry_id in &entry_ids {
+        match get_outbox_entry(entry_id) {
+            Ok(entry) => entries.push(entry),
+            Err(err) => {
+                errors.push(err);
+
+                response.errors = Some(errors);
+                return Err(response)
+            }
+        }
+    }
+
+    let next_offset = offset + entry_ids.len();
+    let next_cursor = if (next_offset as u64) < total && !entry_ids.is_empty() { Some(next_offset.to_string()) } else { None };
+
+    Ok(OutboxPage{ entries, total, next_cursor })
+}
+
+// Queues again a dead letter entry, resetting its attempts
+pub fn redrive_dead_letter(entry_id: &str) -> Result<StandardResponse, StandardResponse> {
+    let mut response: StandardResponse = StandardResponse::new();
+    let mut errors: Vec<String> = vec![];
+
+    let dead_letter = is_dead_letter(entry_id);
+
+    if dead_letter.is_err() {
+        errors.push(dead_letter.unwrap_err());
+
+        response.errors = Some(errors);
+        return Err(response)
+    }
+
+    if !dead_letter.unwrap() {
+        response.not_found = true;
+        errors.push(format!("Dead letter {} not found", entry_id));
+
+        response.errors = Some(errors);
+        return Err(response)
+    }
+
+    let mut entry = match get_outbox_entry(entry_id) {
+        Ok(entry) => entry,
+        Err(err) => {
+            errors.push(err);
+
+            response.errors = Some(errors);
+            return Err(response)
+        }
+    };
+    entry.attempts = 0;
+
+    let timestamp = match SystemTime::now().duration_since(UNIX_EPOCH) {
+        Ok(n) => n.as_millis() as u64,
+        Err(_) => panic!("SystemTime before UNIX EPOCH!"),
+    };
+
+    info!("Re-driving dead letter {}", entry_id);
+    let res = schedule_outbox_entry(&entry, timestamp);
+
+    if res.is_err() {
+        errors.push(res.unwrap_err());
+
+        response.errors = Some(errors);
+        return Err(response)
+    }
+
+    response.references = vec![ModifiedReference{ system: "OUTBOX".to_string(), reference: entry.id }];
+    Ok(response)
+}
+
+// Handles an event received directly from Meta, doing what whatsapp-manager does before notifying this system
+pub async fn webhook_event(event: &Event, raw_event: &serde_json::Value) -> Result<StandardResponse, StandardResponse> {
+    let mut response: StandardResponse = StandardResponse::new();
+    let mut errors: Vec<String> = vec![];
+
+    // Status updates for sent messages are handled apart from user messages
+    for status in event.statuses() {
+        let res = webhook_status(status);
+
+        if res.is_err() {
+            errors.push(res.unwrap_err());
+        }
+    }
+
+    // Messages are processed one by one in the order they were sent, a failing message doesn't stop the rest
+    let contact_name = event.contact_name();
+    for message in event.messages() {
+        match webhook_message(message, contact_name.as_deref(), raw_event).await {
+            Ok(res) => response.references.extend(res.references),
+            Err(res) => errors.extend(res.errors.unwrap_or_default()),
+        }
+    }
+
+    if !errors.is_empty() {
+        response.errors = Some(errors);
+        return Err(response)
+    }
+
+    Ok(response)
+}
+
+// Updates the delivery of a message sent for a tracker step, failed messages are sent again
+fn webhook_status(status: &Status) -> Result<(), String> {
+    info!("Message {} to {} is {}", status.id, status.recipient_id, status.status);
+
+    // Meta timestamps are in seconds
+    let timestamp = match status.timestamp.parse::<u64>() {
+        Ok(timestamp) => timestamp * 1000,
+        Err(_) => match SystemTime::now().duration_since(UNIX_EPOCH) {
+            Ok(n) => n.as_millis() as u64,
+            Err(_) => panic!("SystemTime before UNIX EPOCH!"),
+        },
+    };
+
+    let error = status.errors.as_ref()
+        .and_then(|errors| errors.get(0))
+        .map(|error| format!("{}: {}", error.code, error.title));
+
+    let delivery = update_message_delivery(&status.id, &status.status, timestamp, error.as_deref());
+
+    if delivery.is_err() {
+        return Err(delivery.unwrap_err())
+    }
+
+    if delivery.as_ref().unwrap().is_none() {
+        debug!("Message {} wasn't sent for a tracker step, skipping status", status.id);
+        return Ok(())
+    }
+
+    let delivery = delivery.unwrap().unwrap();
+
+    if status.status == "failed" {
+        return redeliver_failed_message(&delivery)
+    }
+
+    Ok(())
+}
+
+async fn webhook_message(message: &Message, contact_name: Option<&str>, raw_event: &serde_json::Value) -> Result<StandardResponse, StandardResponse> {
+    let mut response: StandardResponse = StandardResponse::new();
+    let mut errors: Vec<String> = vec![];
+
+    let phone_number = message.from.clone();
+    let message_id = message.id.clone();
+
+    // Meta may deliver the same message more than once
+    let first_delivery = mark_message_processed(&message_id);
+
+    if first_delivery.is_err() {
+        errors.push(first_delivery.unwrap_err());
+
+        response.errors = Some(errors);
+        return Err(response)
+    }
+
+    if !first_delivery.unwrap() {
+        info!("Message {} was already processed, skipping it", message_id);
+        return Ok(response)
+    }
+
+    // Whole event is stored under each message id, the flow picks the message by its id
+    let res = save_user_message(&message_id, &phone_number, raw_event);
+
+    if res.is_err() {
+        errors.push(res.unwrap_err());
+        clear_processed_mark(&message_id);
+
+        response.errors = Some(errors);
+        return Err(response)
+    }
+
+    let res = route_inbound(&phone_number, &message_id, contact_name).await;
+
+    // Failed messages can be handled again when Meta retries the delivery
+    if res.is_err() {
+        clear_processed_mark(&message_id);
+    }
+
+    res
+}
+
+fn clear_processed_mark(message_id: &str) {
+    let res = unmark_message_processed(message_id);
+
+    if res.is_err() {
+        error!("{}", res.unwrap_err());
+    }
+}
+
+// Runs the flow for a stored message received by this system instead of whatsapp-manager
+pub async fn route_inbound(user_id: &str, message_id: &str, contact_name: Option<&str>) -> Result<StandardResponse, StandardResponse> {
+    let mut response: StandardResponse = StandardResponse::new();
+    let mut errors: Vec<String> = vec![];
+
+    let timestamp = match SystemTime::now().duration_since(UNIX_EPOCH) {
+        Ok(n) => n.as_millis().to_string(),
+        Err(_) => panic!("SystemTime before UNIX EPOCH!"),
+    };
+
+    // Logged as coming from whatsapp-manager so the flow handles it the same way
+    let log = MessageLog{
+        timestamp,
+        destination_systems: vec![SYSTEM_ID.to_string()],
+        origin_system: "1".to_string(),
+        phone_number: user_id.to_string(),
+        origin: "INCOMING".to_string(),
+        register_id: message_id.to_string(),
+    };
+
+    // User is already talking with this system
+    if get_user_mode(user_id).map(|mode| mode == SYSTEM_ID as u16).unwrap_or(false) {
+        return incoming_message(log).await
+    }
+
+    info!("Starting request for {}", user_id);
+    let res = set_user_mode(user_id, SYSTEM_ID);
+
+    if res.is_err() {
+        errors.push(res.unwrap_err());
+
+        response.errors = Some(errors);
+        return Err(response)
+    }
+
+    let started = outgoing_message(log.clone(), contact_name).await;
+
+    if started.is_err() {
+        return started
+    }
+
+    let tracker = get_last_tracker(user_id);
+
+    if tracker.is_err() {
+        errors.push(format!("Error obtaining last tracker {}", tracker.unwrap_err()));
+
+        response.errors = Some(errors);
+        return Err(response)
+    }
+
+    // Resume prompt was sent instead of creating a tracker, the user answer continues the flow
+    if tracker.as_ref().unwrap().resume_prompt_sent {
+        return started
+    }
+
+    let step = get_last_tracker_step(&tracker.as_ref().unwrap().id);
+
+    if step.is_err() {
+        errors.push(format!("Error obtaining last step {}", step.unwrap_err()));
+
+        response.errors = Some(errors);
+        return Err(response)
+    }
+
+    // Run the system steps following the tracker creation, or tell the user how to start the request
+    continue_flow(&log, tracker.as_ref().unwrap(), step.as_ref().unwrap()).await
+}
+
+// User messages open the customer service window and update the customer profile, failures are only logged.
+// Returns whether the window was closed before the message
+fn record_inbound(log: &MessageLog, contact_name: Option<&str>) -> bool {
+    let window_reopened = !is_within_service_window(&log.phone_number);
+
+    let timestamp = log.timestamp.parse::<u64>().unwrap_or_else(|_| match SystemTime::now().duration_since(UNIX_EPOCH) {
+        Ok(n) => n.as_millis() as u64,
+        Err(_) => panic!("SystemTime before UNIX EPOCH!"),
+    });
+
+    let res = set_last_inbound(&log.phone_number, timestamp);
+
+    if res.is_err() {
+        error!("Error recording inbound message: {}", res.unwrap_err());
+    }
+
+    let res = touch_customer_profile(&log.phone_number, contact_name, timestamp);
+
+    if res.is_err() {
+        error!("Error updating customer profile: {}", res.unwrap_err());
+    }
+
+    window_reopened
+}
+
+pub fn get_customer_profile(phone_number: &str) -> Result<CustomerProfile, StandardResponse> {
+    let mut response: StandardResponse = StandardResponse::new();
+
+    let profile = get_profile(phone_number);
+
+    if profile.is_err() {
+        let err = profile.unwrap_err();
+        response.not_found = err.contains("No records found");
+        response.errors = Some(vec![err]);
+        return Err(response)
+    }
+
+    Ok(profile.unwrap())
+}
+
+fn has_saved_vehicles(phone_number: &str) -> bool {
+    get_profile(phone_number).map(|profile| !profile.vehicles.is_empty()).unwrap_or(false)
+}
+
+// Obtains the step following the given status, if the user is editing a field from the request summary
+// the flow goes back to the summary once the edited field is provided
+fn get_next_step(tracker: &RequestTracker, status: FlowStatus) -> Option<FlowStatus> {
+    if tracker.edit_return_status == (status as u16).to_string() {
+        info!("Edited field provided, returning to request summary");
+        return Some(FlowStatus::RequestSummarySent)
+    }
+
+    // Returning customers start choosing one of their saved vehicles
+    if status == FlowStatus::FlowStarted && has_saved_vehicles(&tracker.phone_number) {
+        return Some(FlowStatus::GarageListSent)
+    }
+
+    status.value().next_step.map(|next_step| FlowStatus::get_from_value(&(next_step as u16).to_string()))
+}
+
+pub fn get_tracker_steps(tracker_id: &str, params: &TrackerParam) -> Result<TrackerStepsResponse, StandardResponse> {
+
+    let mut response: StandardResponse = StandardResponse::new();
+    let mut errors: Vec<String> = vec![];
+
+    let tracker = get_tracker(tracker_id);
+
+    if tracker.is_err() {
+        return Err(tracker_error(tracker_id, tracker.unwrap_err()))
+    }
+
+    // Cursor holds the offset of the next page
+    let offset = match &params.cursor {
+        Some(cursor) => match cursor.parse::<usize>() {
+            Ok(offset) => offset,
+            Err(_) => {
+                errors.push(format!("Invalid cursor {}", cursor));
+
+                response.errors = Some(errors);
+                return Err(response)
+            }
+        },
+        None => 0,
+    };
+
+    info!("Obtaining tracker {} steps", tracker_id);
+    let (steps, next_cursor) = if params.limit.is_some() {
+        let page = get_tracker_steps_page(tracker_id, offset, params.limit.unwrap().min(100));
+
+        if page.is_err() {
+            error!("Error retrieving tracker steps {}", page.as_ref().unwrap_err());
+            errors.push(page.unwrap_err());
+
+            response.errors = Some(errors);
+            return Err(response)
+        }
+
+        let (total, steps) = page.unwrap();
+        let next_offset = offset + steps.len();
+        let next_cursor = if (next_offset as u64) < total && !steps.is_empty() { Some(next_offset.to_string()) } else { None };
+
+        (steps, next_cursor)
+    } else {
+        let steps = get_all_steps(tracker_id);
+
+        if steps.is_err() {
+            error!("Error retrieving tracker steps {}", steps.as_ref().unwrap_err());
+            errors.push(steps.unwrap_err());
+
+            response.errors = Some(errors);
+            return Err(response)
+        }
+
+        (steps.unwrap().into_iter().skip(offset).collect(), None)
+    };
+
+    let steps: Vec<TrackerStepView> = steps.into_iter()
+        .map(|step| TrackerStepView{ status_name: FlowStatus::get_from_value(&step.status).name(), step })
+        .collect();
+
+    // Requests without limit keep receiving the bare list of steps
+    if params.limit.is_none() {
+        return Ok(TrackerStepsResponse::All(steps))
+    }
+
+    Ok(TrackerStepsResponse::Page(TrackerStepsPage{ steps, next_cursor }))
+}
+
+pub fn get_tracker_summary(tracker_id: &str) -> Result<PartRequest, StandardResponse> {
+
+    let mut response: StandardResponse = StandardResponse::new();
+
+    let tracker = get_tracker(tracker_id);
+
+    if tracker.is_err() {
+        return Err(tracker_error(tracker_id, tracker.unwrap_err()))
+    }
+
+    let part_request = build_part_request(tracker.as_ref().unwrap());
+
+    if part_request.is_err() {
+        error!("Error building part request {}", part_request.as_ref().unwrap_err());
+        response.errors = Some(vec![format!("Error building part request {}", part_request.unwrap_err())]);
+        return Err(response)
+    }
+
+    Ok(part_request.unwrap())
+}
+
+// Projects the tracker steps into the part request, contact name is taken from the latest user message
+fn build_part_request(tracker: &RequestTracker) -> Result<PartRequest, String> {
+    let steps = get_all_steps(&tracker.id);
+
+    if steps.is_err() {
+        return Err(steps.unwrap_err())
+    }
+
+    let steps = steps.unwrap();
+
+    // Name recorded in the customer profile from the messages received
+    let contact_name = get_profile(&tracker.phone_number).ok().and_then(|profile| profile.name);
+
+    Ok(PartRequest::from_steps(tracker, &steps, contact_name))
+}
+
+// Tracker APIs accept the ticket number shown to customers in place of the tracker id
+pub fn resolve_tracker_id(tracker_id: &str) -> Result<String, StandardResponse> {
+    let ticket_number = parse_ticket_number(tracker_id);
+
+    if ticket_number.is_none() {
+        return Ok(tracker_id.to_string())
+    }
+
+    let query = format!("@ticket_number:{{{}}}", escape_query_value(ticket_number.as_ref().unwrap()));
+    let res = crate::redis::search_trackers(&query, "timestamp", false, 0, 1);
+
+    if res.is_err() {
+        return Err(tracker_error(tracker_id, res.unwrap_err()))
+    }
+
+    match res.unwrap().1.first() {
+        Some(tracker) => Ok(tracker.id.clone()),
+        None => Err(tracker_error(tracker_id, "No records found".to_string())),
+    }
+}
+
+// Error response for a tracker that couldn't be obtained, flagged as not found when it doesn't exist
+fn tracker_error(tracker_id: &str, err: String) -> StandardResponse {
+    let mut response: StandardResponse = StandardResponse::new();
+
+    if err.contains("No records found") {
+        response.not_found = true;
+        response.errors = Some(vec![format!("Tracker {} not found", tracker_id)]);
+    } else {
+        response.errors = Some(vec![format!("Error obtaining tracker {}: {}", tracker_id, err)]);
+    }
+
+    response
+}
+
+// Builds a chronologically ordered timeline with user messages, sent messages and step transitions of the tracker
+pub fn get_tracker_transcript(tracker_id: &str) -> Result<Vec<TranscriptEntry>, StandardResponse> {
+    let mut response: StandardResponse = StandardResponse::new();
+    let mut errors: Vec<String> = vec![];
+
+    let tracker = get_tracker(tracker_id);
+
+    if tracker.is_err() {
+        return Err(tracker_error(tracker_id, tracker.unwrap_err()))
+    }
+    let tracker = tracker.unwrap();
+
+    let steps = get_all_steps(tracker_id);
+
+    if steps.is_err() {
+        errors.push(format!("Error retrieving tracker steps {}", steps.unwrap_err()));
+
+        response.errors = Some(errors);
+        return Err(response)
+    }
+
+    let mut transcript: Vec<TranscriptEntry> = vec![];
+    let mut message_references: HashSet<String> = HashSet::new();
+    let mut last_step_timestamp: u64 = 0;
+
+    info!("Obtaining messages for tracker {} steps", tracker_id);
+    for step in steps.unwrap() {
+        let step_timestamp = step.timestamp.parse::<u64>().unwrap_or(0);
+        last_step_timestamp = last_step_timestamp.max(step_timestamp);
+
+        // Only user messages are stored as incoming messages, other references fail the lookup
+        if step.origin != StepOrigin::System.value() && step.origin != StepOrigin::Agent.value()
+            && !step.message_reference.is_empty() && message_references.insert(step.message_reference.clone()) {
+
+            let message = get_channel(&tracker.phone_number).get_message(&tracker.phone_number, &step.message_reference);
+
+            if let Ok(message) = message {
+                transcript.push(TranscriptEntry::Incoming {
+                    timestamp: if message.timestamp > 0 { message.timestamp } else { step_timestamp },
+                    message_id: message.id,
+                    message_type: message.channel_type,
+                    content: message.content,
+                });
+            }
+        }
+
+        transcript.push(TranscriptEntry::Step {
+            timestamp: step_timestamp,
+            step_id: step.id.clone(),
+            status: step.status.clone(),
+            status_name: FlowStatus::get_from_value(&step.status).name(),
+            value: step.value.clone(),
+            origin: step.origin.clone(),
+        });
+    }
+
+    // Messages can still be sent to trackers in progress
+    let from = tracker.timestamp.parse::<u64>().unwrap_or(0);
+    let to = if tracker.state == TrackerState::Active.value() || tracker.state == TrackerState::Handoff.value() {
+        None
+    } else {
+        Some(last_step_timestamp)
+    };
+
+    let outgoing_messages = get_outgoing_messages(&tracker.phone_number, from, to);
+
+    if outgoing_messages.is_err() {
+        errors.push(format!("Error obtaining outgoing messages: {}", outgoing_messages.unwrap_err()));
+
+        response.errors = Some(errors);
+        return Err(response)
+    }
+
+    // Older messages aren't linked to a tracker and are only filtered by time
+    for outgoing_message in outgoing_messages.unwrap().into_iter().filter(|message| message.tracker_id.is_empty() || message.tracker_id == tracker.id) {
+        // Delivery is only tracked for messages sent through the outbox
+        let delivery = if !outgoing_message.reference.is_empty() {
+            get_message_delivery(&outgoing_message.reference).unwrap_or(None)
+        } else {
+            None
+        };
+
+        transcript.push(TranscriptEntry::Outgoing {
+            timestamp: outgoing_message.timestamp,
+            reference: outgoing_message.reference,
+            message: outgoing_message.message,
+            delivery,
+        });
+    }
+
+    transcript.sort_by_key(|entry| entry.timestamp());
+
+    Ok(transcript)
+}
+
+pub fn search_trackers(params: &TrackerSearchParams) -> Result<TrackerPage, StandardResponse> {
+    let mut response: StandardResponse = StandardResponse::new();
+    let mut errors: Vec<String> = vec![];
+
+    let mut filters: Vec<String> = vec![];
+
+    if params.phone.is_some() {
+        filters.push(format!("@phone_number:{}", escape_query_value(params.phone.as_ref().unwrap())));
+    }
+
+    if params.status.is_some() {
+        // Status can be provided by id or by name
+        let status_param = params.status.as_ref().unwrap();
+        let status = all::<FlowStatus>().find(|status| (*status as u16).to_string() == *status_param || status.name() == *status_param);
+
+        if status.is_none() {
+            errors.push(format!("Status {} is not a valid flow status", status_param));
+
+            response.errors = Some(errors);
+            return Err(response)
+        }
+
+        filters.push(format!("@status:{{{}}}", status.unwrap() as u16));
+    }
+
+    if params.ticket.is_some() {
+        let ticket = params.ticket.as_ref().unwrap();
+        let ticket_number = parse_ticket_number(ticket).unwrap_or(ticket.clone());
+        filters.push(format!("@ticket_number:{{{}}}", escape_query_value(&ticket_number)));
+    }
+
+    for (field, value) in [("state", &params.state), ("brand", &params.brand), ("model", &params.model)] {
+        if value.is_some() {
+            filters.push(format!("@{}:{{{}}}", field, escape_query_value(value.as_ref().unwrap())));
+        }
+    }
+
+    if params.created_from.is_some() || params.created_to.is_some() {
+        let from = params.created_from.map(|from| from.to_string()).unwrap_or("-inf".to_string());
+        let to = params.created_to.map(|to| to.to_string()).unwrap_or("+inf".to_string());
+        filters.push(format!("@timestamp:[{} {}]", from, to));
+    }
+
+    let query = if filters.is_empty() { "*".to_string() } else { filters.join(" ") };
+
+    let sort_by = match params.sort.as_deref() {
+        None | Some("created") => "timestamp",
+        Some("updated") => "updated",
+        Some(sort) => {
+            errors.push(format!("Sort {} is not supported, expected created or updated", sort));
+
+            response.errors = Some(errors);
+            return Err(response)
+        }
+    };
+    let ascending = params.order.as_deref() == Some("asc");
+
+    // Cursor holds the offset of the next page
+    let limit = params.limit.unwrap_or(20).min(100);
+    let offset = match &params.cursor {
+        Some(cursor) => match cursor.parse::<usize>() {
+            Ok(offset) => offset,
+            Err(_) => {
+                errors.push(format!("Invalid cursor {}", cursor));
+
+                response.errors = Some(errors);
+                return Err(response)
+            }
+        },
+        None => 0,
+    };
+
+    info!("Searching trackers with query {}", query);
+    let res = crate::redis::search_trackers(&query, sort_by, ascending, offset, limit);
+
+    if res.is_err() {
+        error!("Error searching trackers {}", res.as_ref().unwrap_err());
+        errors.push(res.unwrap_err());
+
+        response.errors = Some(errors);
+        return Err(response)
+    }
+
+    let (total, trackers) = res.unwrap();
+    let next_offset = offset + trackers.len();
+
+    Ok(TrackerPage{
+        trackers: trackers.iter().map(TrackerSummary::from).collect(),
+        total,
+        next_cursor: if (next_offset as u64) < total && !trackers.is_empty() { Some(next_offset.to_string()) } else { None },
+    })
+}
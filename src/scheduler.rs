@@ -0,0 +1,150 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use fizzy_commons::shared_structs::{MessageContent, MessageRequest};
+use crate::constants::{DEFAULT_LANGUAGE, REMINDER_TEMPLATE_NAME, SYSTEM_ID, TrackerState};
+use actix_web::web;
+use crate::redis::{get_inactive_trackers, get_tracker, publish_message, reset_user_mode, set_tracker_field, set_tracker_state, try_lock};
+use crate::structs::{MessageLog, MessageTemplate, RequestTracker, WorkflowEvent};
+use crate::outbox::queue_message;
+use crate::tools::get_env_setting;
+
+// Periodically checks for trackers without new steps, reminding the user and expiring them
+pub async fn start_inactivity_scheduler() {
+    let check_interval = get_env_setting("INACTIVITY_CHECK_SECONDS", 60);
+    let mut interval = actix_web::rt::time::interval(Duration::from_secs(check_interval));
+
+    info!("Starting inactivity scheduler, checking every {} seconds", check_interval);
+    loop {
+        interval.tick().await;
+
+        // Every replica runs the scheduler, the lock is held for the whole interval so only one of them checks
+        let res = web::block(move || {
+            let locked = try_lock("inactivity-scheduler", check_interval * 1000);
+
+            if locked.is_err() {
+                return Err(locked.unwrap_err())
+            }
+
+            if !locked.unwrap() {
+                debug!("Inactivity check taken by another instance");
+                return Ok(())
+            }
+
+            check_inactive_trackers()
+        }).await;
+
+        match res {
+            Ok(Err(err)) => error!("Error checking inactive trackers: {}", err),
+            Err(err) => error!("Error running inactivity check: {}", err),
+            _ => {}
+        }
+    }
+}
+
+pub fn check_inactive_trackers() -> Result<(), String> {
+    let reminder_after = get_env_setting("INACTIVITY_REMINDER_MINUTES", 30) * 60 * 1000;
+    let expire_after = get_env_setting("INACTIVITY_EXPIRY_MINUTES", 1440) * 60 * 1000;
+
+    let now = match SystemTime::now().duration_since(UNIX_EPOCH) {
+        Ok(n) => n.as_millis() as u64,
+        Err(_) => panic!("SystemTime before UNIX EPOCH!"),
+    };
+
+    let inactive_trackers = get_inactive_trackers(now.saturating_sub(reminder_after));
+
+    if inactive_trackers.is_err() {
+        return Err(inactive_trackers.unwrap_err())
+    }
+
+    for (tracker_id, last_activity) in inactive_trackers.unwrap() {
+        debug!("Tracker {} inactive since {}", tracker_id, last_activity);
+
+        let tracker = get_tracker(&tracker_id);
+
+        if tracker.is_err() {
+            error!("Error obtaining inactive tracker {}: {}", tracker_id, tracker.unwrap_err());
+            continue
+        }
+
+        let res = if now.saturating_sub(last_activity) >= expire_after {
+            expire_tracker(tracker.as_ref().unwrap())
+        } else if !tracker.as_ref().unwrap().reminder_sent {
+            send_reminder(tracker.as_ref().unwrap())
+        } else {
+            Ok(())
+        };
+
+        if res.is_err() {
+            error!("Error handling inactive tracker {}: {}", tracker_id, res.unwrap_err());
+        }
+    }
+
+    Ok(())
+}
+
+fn send_reminder(tracker: &RequestTracker) -> Result<(), String> {
+    info!("Sending inactivity reminder for tracker {}", tracker.id);
+
+    let reminder_message = MessageRequest{
+        system_id: SYSTEM_ID,
+        to: vec![tracker.phone_number.clone()],
+        message_type: "text".to_string(),
+        content: MessageContent {
+            body: Some("Tu solicitud de repuesto sigue pendiente, responde el ultimo mensaje para continuar.".to_string()),
+            list: None,
+            buttons: None,
+        },
+    };
+
+    let reminder_template = MessageTemplate{
+        name: REMINDER_TEMPLATE_NAME.to_string(),
+        language: DEFAULT_LANGUAGE.to_string(),
+        parameters: vec![],
+    };
+
+    let res = queue_message(&tracker.id, reminder_message, Some(reminder_template));
+
+    if res.is_err() {
+        return Err(format!("Error queueing reminder message: {}", res.unwrap_err()))
+    }
+
+    set_tracker_field(&tracker.id, "reminder_sent", "1")
+}
+
+fn expire_tracker(tracker: &RequestTracker) -> Result<(), String> {
+    info!("Expiring tracker {}", tracker.id);
+
+    let res = set_tracker_state(&tracker.id, TrackerState::Expired);
+
+    if res.is_err() {
+        return Err(res.unwrap_err())
+    }
+
+    let res = reset_user_mode(&tracker.phone_number);
+
+    if res.is_err() {
+        return Err(res.unwrap_err())
+    }
+
+    let timestamp = match SystemTime::now().duration_since(UNIX_EPOCH) {
+        Ok(n) => n.as_millis().to_string(),
+        Err(_) => panic!("SystemTime before UNIX EPOCH!"),
+    };
+
+    // Notify whatsapp-manager, which handles user mode selection
+    let expired_log = MessageLog{
+        timestamp,
+        destination_systems: vec!["1".to_string()],
+        origin_system: SYSTEM_ID.to_string(),
+        phone_number: tracker.phone_number.clone(),
+        origin: "EXPIRED".to_string(),
+        register_id: tracker.id.clone(),
+    };
+
+    let res = publish_message(&expired_log, &tracker.phone_number, &WorkflowEvent::new("TrackerExpired", &tracker.id, ""));
+
+    if res.is_err() {
+        return Err(format!("Error publishing expiry message: {}", res.unwrap_err()))
+    }
+
+    Ok(())
+}
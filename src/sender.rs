@@ -0,0 +1,404 @@
+use std::sync::OnceLock;
+use fizzy_commons::shared_structs::MessageRequest;
+use serde_json::{json, Value};
+use crate::circuit_breaker::{graph_api_breaker, whatsapp_manager_breaker};
+use crate::structs::{MessageTemplate, ModifiedReference, StandardResponse};
+use crate::tools::{graph_api_url, http_agent};
+
+// Delivers outgoing messages to WhatsApp
+pub trait MessageSender: Send + Sync {
+    fn send(&self, message: &MessageRequest) -> Result<StandardResponse, String>;
+}
+
+// Sends messages through the whatsapp-manager service
+pub struct ManagerSender {
+    host: String,
+}
+
+impl ManagerSender {
+    pub fn new(host: &str) -> ManagerSender {
+        ManagerSender { host: host.to_string() }
+    }
+}
+
+impl MessageSender for ManagerSender {
+    fn send(&self, message: &MessageRequest) -> Result<StandardResponse, String> {
+        let resp = whatsapp_manager_breaker().call(|| {
+            http_agent().post(format!("{}/message", self.host).as_str())
+                .send_json(json!(message))
+        });
+
+        if resp.is_err() {
+            return Err(resp.unwrap_err())
+        }
+
+        let resp = resp.unwrap().into_string();
+
+        if resp.is_err() {
+            return Err(resp.unwrap_err().to_string())
+        }
+
+        let parsed_response: Result<StandardResponse, serde_json::Error> = serde_json::from_str(&resp.unwrap());
+
+        if parsed_response.is_err() {
+            return Err(format!("Invalid whatsapp-manager response: {}", parsed_response.unwrap_err()))
+        }
+
+        Ok(parsed_response.unwrap())
+    }
+}
+
+// Sends messages directly to the Meta Cloud API, for deployments without whatsapp-manager
+pub struct CloudApiSender {
+    base_url: String,
+    phone_number_id: String,
+    token: String,
+}
+
+impl CloudApiSender {
+    pub fn new(base_url: &str, phone_number_id: &str, token: &str) -> CloudApiSender {
+        CloudApiSender {
+            base_url: base_url.to_string(),
+            phone_number_id: phone_number_id.to_string(),
+            token: token.to_string(),
+        }
+    }
+}
+
+impl MessageSender for CloudApiSender {
+    fn send(&self, message: &MessageRequest) -> Result<StandardResponse, String> {
+        let mut response = StandardResponse::new();
+        let mut errors: Vec<String> = vec![];
+
+        // Cloud API accepts a single recipient per message, a failed recipient doesn't stop the rest
+        for to in &message.to {
+            let res = self.send_to(message, to);
+
+            if res.is_err() {
+                errors.push(format!("Error sending message to {}: {}", to, res.unwrap_err()));
+                continue
+            }
+
+            response.references.push(ModifiedReference{ system: "WHATSAPP".to_string(), reference: res.unwrap() });
+        }
+
+        // Recipients already sent are returned along with the errors of the failed ones
+        if response.references.is_empty() && !errors.is_empty() {
+            return Err(errors.join(", "))
+        }
+
+        if !errors.is_empty() {
+            response.errors = Some(errors);
+        }
+
+        Ok(response)
+    }
+}
+
+impl CloudApiSender {
+    // Sends the message to a single recipient, returning the WhatsApp message id
+    fn send_to(&self, message: &MessageRequest, to: &str) -> Result<String, String> {
+        let payload = to_cloud_api_payload(message, to);
+
+        if payload.is_err() {
+            return Err(payload.unwrap_err())
+        }
+
+        let resp = graph_api_breaker().call(|| {
+            http_agent().post(format!("{}/{}/messages", self.base_url, self.phone_number_id).as_str())
+                .set("Authorization", format!("Bearer {}", self.token).as_str())
+                .send_json(payload.unwrap())
+        });
+
+        if resp.is_err() {
+            return Err(resp.unwrap_err())
+        }
+
+        let resp: Result<Value, std::io::Error> = resp.unwrap().into_json();
+
+        if resp.is_err() {
+            return Err(format!("Invalid Cloud API response: {}", resp.unwrap_err()))
+        }
+
+        let message_id = resp.as_ref().unwrap()["messages"][0]["id"].as_str();
+
+        if message_id.is_none() {
+            return Err(format!("Cloud API response without message id: {}", resp.unwrap()))
+        }
+
+        Ok(message_id.unwrap().to_string())
+    }
+}
+
+// Cloud API rejects interactive messages with longer titles
+const MAX_BUTTON_TITLE_LENGTH: usize = 20;
+const MAX_ROW_TITLE_LENGTH: usize = 24;
+
+// Translates the message into the Cloud API format, media messages carry the media url as body
+pub fn to_cloud_api_payload(message: &MessageRequest, to: &str) -> Result<Value, String> {
+    let body = message.content.body.clone().unwrap_or_default();
+
+    let content = match message.message_type.as_str() {
+        "text" => json!({
+            "type": "text",
+            "text": { "body": body },
+        }),
+        "list" => {
+            let list = message.content.list.as_ref().ok_or("List message without list")?;
+            let rows: Vec<Value> = list.choices.iter()
+                .map(|choice| json!({ "id": choice.id, "title": truncate(&choice.value, MAX_ROW_TITLE_LENGTH) }))
+                .collect();
+
+            json!({
+                "type": "interactive",
+                "interactive": {
+                    "type": "list",
+                    "body": { "text": body },
+                    "action": {
+                        "button": truncate(&list.title, MAX_BUTTON_TITLE_LENGTH),
+                        "sections": [{ "title": truncate(&list.title, MAX_ROW_TITLE_LENGTH), "rows": rows }],
+                    },
+                },
+            })
+        }
+        "button" => {
+            let buttons = message.content.buttons.as_ref().ok_or("Button message without buttons")?;
+            let replies: Vec<Value> = buttons.choices.iter()
+                .map(|choice| json!({ "type": "reply", "reply": { "id": choice.id, "title": truncate(&choice.value, MAX_BUTTON_TITLE_LENGTH) } }))
+                .collect();
+
+            json!({
+                "type": "interactive",
+                "interactive": {
+                    "type": "button",
+                    "body": { "text": body },
+                    "action": { "buttons": replies },
+                },
+            })
+        }
+        "template" => {
+            let template: MessageTemplate = serde_json::from_str(&body).map_err(|err| format!("Invalid template message: {}", err))?;
+            let mut content = json!({
+                "type": "template",
+                "template": {
+                    "name": template.name,
+                    "language": { "code": template.language },
+                },
+            });
+
+            if !template.parameters.is_empty() {
+                let parameters: Vec<Value> = template.parameters.iter()
+                    .map(|parameter| json!({ "type": "text", "text": parameter }))
+                    .collect();
+                content["template"]["components"] = json!([{ "type": "body", "parameters": parameters }]);
+            }
+
+            content
+        }
+        "image" | "document" | "audio" | "video" => {
+            let mut media = json!({ "type": message.message_type });
+            media[message.message_type.as_str()] = json!({ "link": body });
+
+            media
+        }
+        _ => return Err(format!("Message type {} not supported by the Cloud API sender", message.message_type)),
+    };
+
+    let mut payload = json!({
+        "messaging_product": "whatsapp",
+        "recipient_type": "individual",
+        "to": to,
+    });
+    payload.as_object_mut().unwrap().extend(content.as_object().unwrap().clone());
+
+    Ok(payload)
+}
+
+fn truncate(text: &str, max_length: usize) -> String {
+    text.chars().take(max_length).collect()
+}
+
+static SENDER: OnceLock<Box<dyn MessageSender>> = OnceLock::new();
+
+// Creates the sender selected by MESSAGE_SENDER, manager(default) or cloud_api. Called on startup
+// so missing settings stop the service instead of failing the first message sent
+pub fn init_sender() -> Result<(), String> {
+    let sender: Box<dyn MessageSender> = match std::env::var("MESSAGE_SENDER").unwrap_or("manager".to_string()).as_str() {
+        "cloud_api" => {
+            info!("Sending messages through the Cloud API");
+            Box::new(CloudApiSender::new(
+                &graph_api_url(),
+                &required_setting("WHATSAPP_PHONE_NUMBER_ID")?,
+                &required_setting("META_TOKEN")?,
+            ))
+        }
+        "manager" => Box::new(ManagerSender::new(&required_setting("WHATSAPP_MANAGER_HOST")?)),
+        sender => return Err(format!("Unknown MESSAGE_SENDER {}", sender)),
+    };
+
+    if SENDER.set(sender).is_err() {
+        return Err("Message sender already initialized".to_string())
+    }
+
+    Ok(())
+}
+
+fn required_setting(name: &str) -> Result<String, String> {
+    match std::env::var(name) {
+        Ok(value) if !value.trim().is_empty() => Ok(value),
+        _ => Err(format!("{} is required by the selected MESSAGE_SENDER", name)),
+    }
+}
+
+pub fn get_sender() -> &'static dyn MessageSender {
+    SENDER.get().expect("Message sender not initialized").as_ref()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::TcpListener;
+    use std::sync::mpsc;
+    use std::thread;
+    use fizzy_commons::shared_structs::{ButtonMessage, Choice, ListMessage, MessageContent, MessageRequest};
+    use serde_json::Value;
+    use crate::sender::{to_cloud_api_payload, CloudApiSender, ManagerSender, MessageSender};
+
+    // Answers a single request with the given body, sending back the received path and body
+    fn mock_server(response_body: &str) -> (String, mpsc::Receiver<(String, Value)>) {
+        mock_server_responses(vec![(200, response_body)])
+    }
+
+    // Answers each request with the next status and body
+    fn mock_server_responses(responses: Vec<(u16, &str)>) -> (String, mpsc::Receiver<(String, Value)>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let responses: Vec<(u16, String)> = responses.into_iter().map(|(status, body)| (status, body.to_string())).collect();
+        let (sender, receiver) = mpsc::channel();
+
+        thread::spawn(move || for (status, response_body) in responses {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+
+            let mut request_line = String::new();
+            reader.read_line(&mut request_line).unwrap();
+
+            let mut content_length = 0;
+            loop {
+                let mut header = String::new();
+                reader.read_line(&mut header).unwrap();
+
+                if header.trim().is_empty() {
+                    break
+                }
+
+                if header.to_lowercase().starts_with("content-length:") {
+                    content_length = header[15..].trim().parse().unwrap();
+                }
+            }
+
+            let mut body = vec![0; content_length];
+            reader.read_exact(&mut body).unwrap();
+
+            let path = request_line.split_whitespace().nth(1).unwrap().to_string();
+            sender.send((path, serde_json::from_slice(&body).unwrap())).unwrap();
+
+            write!(stream, "HTTP/1.1 {} OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}", status, response_body.len(), response_body).unwrap();
+        });
+
+        (url, receiver)
+    }
+
+    #[test]
+    fn cloud_api_sender_sends_button_message() {
+        let (url, receiver) = mock_server(r#"{"messages":[{"id":"wamid.test"}]}"#);
+        let sender = CloudApiSender::new(&url, "12345", "token");
+
+        let message = MessageRequest{
+            system_id: 3,
+            to: vec!["56912345678".to_string()],
+            message_type: "button".to_string(),
+            content: MessageContent {
+                body: Some("Confirma la solicitud".to_string()),
+                list: None,
+                buttons: Some(ButtonMessage{
+                    title: "Opciones".to_string(),
+                    choices: vec![Choice{ id: "confirm-id".to_string(), value: "Confirmar".to_string() }],
+                }),
+            },
+        };
+
+        let response = sender.send(&message).unwrap();
+        assert_eq!(response.references[0].reference, "wamid.test");
+
+        let (path, payload) = receiver.recv().unwrap();
+        assert_eq!(path, "/12345/messages");
+        assert_eq!(payload["to"], "56912345678");
+        assert_eq!(payload["type"], "interactive");
+        assert_eq!(payload["interactive"]["type"], "button");
+        assert_eq!(payload["interactive"]["body"]["text"], "Confirma la solicitud");
+        assert_eq!(payload["interactive"]["action"]["buttons"][0]["reply"]["id"], "confirm-id");
+    }
+
+    fn message(message_type: &str, body: &str) -> MessageRequest {
+        MessageRequest{
+            system_id: 3,
+            to: vec!["56912345678".to_string()],
+            message_type: message_type.to_string(),
+            content: MessageContent { body: Some(body.to_string()), list: None, buttons: None },
+        }
+    }
+
+    #[test]
+    fn cloud_api_payloads_follow_message_type() {
+        let payload = to_cloud_api_payload(&message("text", "Hola"), "56912345678").unwrap();
+        assert_eq!(payload["type"], "text");
+        assert_eq!(payload["text"]["body"], "Hola");
+
+        let mut list_message = message("list", "Selecciona la marca");
+        list_message.content.list = Some(ListMessage{
+            title: "Marcas disponibles en el catalogo".to_string(),
+            choices: vec![Choice{ id: "toyota-id".to_string(), value: "Toyota Motor Corporation Japan".to_string() }],
+        });
+
+        let payload = to_cloud_api_payload(&list_message, "56912345678").unwrap();
+        assert_eq!(payload["interactive"]["type"], "list");
+        assert_eq!(payload["interactive"]["action"]["button"], "Marcas disponibles e");
+        assert_eq!(payload["interactive"]["action"]["sections"][0]["rows"][0]["id"], "toyota-id");
+        assert_eq!(payload["interactive"]["action"]["sections"][0]["rows"][0]["title"], "Toyota Motor Corporation");
+
+        let template = r#"{"name": "request_reminder", "language": "es", "parameters": ["Pastillas"]}"#;
+        let payload = to_cloud_api_payload(&message("template", template), "56912345678").unwrap();
+        assert_eq!(payload["template"]["name"], "request_reminder");
+        assert_eq!(payload["template"]["language"]["code"], "es");
+        assert_eq!(payload["template"]["components"][0]["parameters"][0]["text"], "Pastillas");
+    }
+
+    #[test]
+    fn cloud_api_sender_returns_sent_recipients_on_partial_failure() {
+        let (url, _receiver) = mock_server_responses(vec![(500, "{}"), (200, r#"{"messages":[{"id":"wamid.second"}]}"#)]);
+        let sender = CloudApiSender::new(&url, "12345", "token");
+
+        let mut text_message = message("text", "Hola");
+        text_message.to.push("56987654321".to_string());
+
+        let response = sender.send(&text_message).unwrap();
+        assert_eq!(response.references.len(), 1);
+        assert_eq!(response.references[0].reference, "wamid.second");
+        assert!(response.errors.unwrap()[0].contains("56912345678"));
+    }
+
+    #[test]
+    fn manager_sender_returns_manager_references() {
+        let (url, receiver) = mock_server(r#"{"references":[{"system":"WHATSAPP","reference":"wamid.manager"}],"errors":null}"#);
+        let sender = ManagerSender::new(&url);
+
+        let response = sender.send(&message("text", "Hola")).unwrap();
+        assert_eq!(response.references[0].reference, "wamid.manager");
+
+        let (path, payload) = receiver.recv().unwrap();
+        assert_eq!(path, "/message");
+        assert_eq!(payload["to"][0], "56912345678");
+        assert_eq!(payload["content"]["body"], "Hola");
+    }
+}
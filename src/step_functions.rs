@@ -9,9 +9,14 @@ use uuid::Uuid;
 use crate::constants::*;
 use crate::constants::FlowStatusId::*;
 use crate::constants::MessageType::*;
-use crate::redis::{get_list, get_list_size, get_step_by_status, get_user_message, reset_user_mode};
-use crate::structs::{MessageLog, RequestTracker, StepDefinition, TrackerStep};
-use crate::tools::{send_message, upload_image, validate_vin};
+use crate::redis::{assign_ticket_number, create_steps, get_all_steps, get_customer_profile, get_list, get_tracker, get_list_size, get_quote, get_quotes, get_step_by_status, publish_message, reset_user_mode, set_tracker_field, set_tracker_state};
+use crate::structs::{MAX_PART_ITEMS, MessageLog, PartClassification, PartItem, PartRequest, QuoteSelectedEvent, RequestTracker, StepDefinition, SupplierQuote, TrackerStep, WorkflowEvent};
+use crate::channel::get_channel;
+use crate::outbox::queue_message;
+use crate::tools::{personalize_message, upload_image, validate_vin};
+
+// Length of each part description in the summary, so the maximum parts fit in the message
+const MAX_SUMMARY_DESCRIPTION_LENGTH: usize = 80;
 
 pub async fn execute_function(new_step: &mut TrackerStep, status: FlowStatus, log:&MessageLog, message_content: &str) -> Result<MessageRequest, String>{
 
@@ -50,20 +55,87 @@ pub async fn execute_function(new_step: &mut TrackerStep, status: FlowStatus, lo
         FlowStatus::RequestAccepted => {
             request_accepted(&new_step, status, log, message_content)
         }
+        FlowStatus::RequestSummarySent => {
+            request_summary_sent(&new_step, status, log, message_content)
+        }
+        FlowStatus::SummaryReviewed => {
+            summary_reviewed(new_step, status, log, message_content)
+        }
+        FlowStatus::EditFieldListSent => {
+            edit_field_list_sent(&new_step, status, log, message_content)
+        }
+        FlowStatus::EditFieldSelected => {
+            edit_field_selected(new_step, status, log, message_content)
+        }
+        FlowStatus::AgentHandoff => {
+            agent_handoff(&new_step, status, log, message_content)
+        }
+        FlowStatus::PartClassified => {
+            part_classified(&new_step, status, log, message_content)
+        }
+        FlowStatus::QuotesSent => {
+            quotes_sent(new_step, status, log, message_content)
+        }
+        FlowStatus::QuoteSelected => {
+            quote_selected(&new_step, status, log, message_content)
+        }
+        FlowStatus::GarageListSent => {
+            garage_list_sent(&new_step, status, log, message_content)
+        }
+        FlowStatus::GarageVehicleSelected => {
+            garage_vehicle_selected(new_step, status, log, message_content)
+        }
+        FlowStatus::AddPartPromptSent => {
+            add_part_prompt_sent(new_step, status, log, message_content)
+        }
+        FlowStatus::AddPartSelected => {
+            add_part_selected(new_step, status, log, message_content)
+        }
     };
 
-    if res.is_err() {
-        // IMPLEMENT ERROR message
-        unimplemented!()
+    if let Err(err) = res {
+        error!("Error executing function for status {}: {}", status as u16, err);
+        return Err(err)
     }
 
+    let mut message_request = res.unwrap();
+    personalize_message(&mut message_request, &log.phone_number);
+
+    Ok(message_request)
+
+}
 
 
+// Builds again the message sent to the user when the step was created, without updating the step
+pub fn get_step_prompt(step: &TrackerStep, log:&MessageLog) -> Result<MessageRequest, String>{
 
-    Ok(res.unwrap())
+    let status = FlowStatus::get_from_value(&step.status);
+    let mut prompt_step = step.clone();
 
-}
+    let prompt = match status {
+        FlowStatus::BrandModalSent => brand_modal_sent(&mut prompt_step, status, log, ""),
+        FlowStatus::ModelModalSent => model_modal_sent(&prompt_step, status, log, ""),
+        FlowStatus::RequestSummarySent => request_summary_sent(&prompt_step, status, log, ""),
+        FlowStatus::PartClassified => part_classified(&prompt_step, status, log, &step.value),
+        FlowStatus::QuotesSent => quotes_sent(&mut prompt_step, status, log, ""),
+        FlowStatus::GarageListSent => garage_list_sent(&prompt_step, status, log, ""),
+        _ => {
+            if status.value().successful_response.is_none() {
+                return Err(format!("Status {:?} doesnt have a message to send", status))
+            }
+
+            let mut message_request = status.value().successful_response.unwrap();
+            message_request.to.push(log.clone().phone_number);
 
+            Ok(message_request)
+        }
+    };
+
+    prompt.map(|mut message_request| {
+        personalize_message(&mut message_request, &log.phone_number);
+        message_request
+    })
+}
 
 pub fn brand_modal_sent(mut step: &mut TrackerStep, mut status: FlowStatus, log:&MessageLog, message_content: &str) -> Result<MessageRequest, String>  {
 
@@ -323,19 +395,16 @@ async fn description_provided(step: &mut TrackerStep, mut status: FlowStatus, lo
     message_request.to.push(log.clone().phone_number);
 
 
-    let message = get_user_message(&log.register_id, &log.phone_number);
+    let message = get_channel(&log.phone_number).get_message(&log.phone_number, &log.register_id);
 
     if message.is_err() {
-        error!("Error obtaining user message");
+        error!("Error obtaining user message: {}", message.as_ref().unwrap_err());
         return Err("Error obtaining user message".to_string())
     }
 
-    // Check if message have attached files
-    let event = message.unwrap();
-
     // If there's an image attached to message
-    if event.entry[0].changes[0].value.messages.as_ref().unwrap()[0].image.is_some() {
-        let image_id = String::from(&event.entry[0].changes[0].value.messages.as_ref().unwrap()[0].image.as_ref().unwrap().id);
+    if message.as_ref().unwrap().image_id.is_some() {
+        let image_id = message.unwrap().image_id.unwrap();
         let upload_res = upload_image(image_id).await;
 
         if upload_res.is_err() {
@@ -357,9 +426,13 @@ fn identification_provided(mut step: &TrackerStep, mut status: FlowStatus, log:&
     // Set phone number
     message_request.to.push(log.clone().phone_number);
 
-    // determine if vin or patent was matched
+    // Plates don't have a check digit, only VINs are validated
+    if PartRequest::identifier_type(message_content) != "VIN" {
+        return Ok(message_request)
+    }
+
     info!("validating vin: {}", message_content.to_string());
-    let is_valid = validate_vin(message_content.to_string());
+    let is_valid = validate_vin(message_content.to_uppercase());
 
     if !is_valid {
         error!("Provided VIN is not valid");
@@ -376,12 +449,12 @@ fn identification_provided(mut step: &TrackerStep, mut status: FlowStatus, log:&
             },
         };
 
-        let error_res = send_message(message_request);
+        let error_res = queue_message(&step.tracker_id, error_message, None);
 
-        // If sending error message failed
+        // If queueing error message failed
         if error_res.is_err(){
-            error!("Provided VIN is not valid, Error message wasn't sent: {}", error_res.as_ref().unwrap_err());
-            return Err(format!("Error message wasn't sent: {}", error_res.as_ref().unwrap_err()))
+            error!("Provided VIN is not valid, Error message wasn't queued: {}", error_res.as_ref().unwrap_err());
+            return Err(format!("Error message wasn't queued: {}", error_res.as_ref().unwrap_err()))
         }
 
         // If vin is invalid
@@ -493,8 +566,27 @@ fn model_selected(mut step: &mut TrackerStep, mut status: FlowStatus, log:&Messa
 
 fn request_accepted(mut step: &TrackerStep, mut status: FlowStatus, log:&MessageLog, message_content: &str) -> Result<MessageRequest, String>{
 
-    // No required params
+    let tracker = get_tracker(&step.tracker_id);
+
+    if tracker.is_err() {
+        return Err(tracker.unwrap_err())
+    }
+
+    // Trackers created before ticket numbers were introduced get one once they're accepted
+    let mut ticket_number = tracker.unwrap().ticket_number;
+
+    if ticket_number.is_empty() {
+        let assigned = assign_ticket_number(&step.tracker_id);
+
+        if assigned.is_err() {
+            return Err(assigned.unwrap_err())
+        }
+
+        ticket_number = assigned.unwrap();
+    }
+
     let mut message_request = status.value().successful_response.unwrap();
+    message_request.content.body = Some(message_request.content.body.as_ref().unwrap().replace("{ticket}", &ticket_number));
 
     message_request.to.push(log.clone().phone_number);
 
@@ -506,6 +598,13 @@ fn request_accepted(mut step: &TrackerStep, mut status: FlowStatus, log:&Message
         return Err(res.unwrap_err())
     }
 
+    let res = set_tracker_state(&step.tracker_id, TrackerState::Completed);
+
+    if res.is_err() {
+        error!("Failed to close tracker");
+        return Err(res.unwrap_err())
+    }
+
     Ok(message_request)
 }
 
@@ -519,4 +618,389 @@ fn identification_request_sent(mut step: &TrackerStep, mut status: FlowStatus, l
     message_request.to.push(log.clone().phone_number);
 
     Ok(message_request)
-}
\ No newline at end of file
+}
+
+fn request_summary_sent(step: &TrackerStep, status: FlowStatus, log:&MessageLog, message_content: &str) -> Result<MessageRequest, String>{
+
+    let mut message_request = status.value().successful_response.unwrap();
+
+    // Obtain latest value provided for each of the vehicle fields
+    info!("Gathering request summary for tracker {}", &step.tracker_id);
+    let mut summary_steps: Vec<TrackerStep> = vec![];
+    for summary_status in [FlowStatus::BrandSelected, FlowStatus::ModelSelected, FlowStatus::IdentificationProvided] {
+        let summary_step = get_step_by_status(&step.tracker_id, &format!("{}", summary_status as u16));
+
+        if summary_step.is_err() {
+            error!("Error obtaining {:?} step: {}", summary_status, summary_step.as_ref().unwrap_err());
+            return Err(summary_step.unwrap_err())
+        }
+
+        summary_steps.push(summary_step.unwrap());
+    }
+
+    // Every description provided is a part of the request
+    let steps = get_all_steps(&step.tracker_id);
+
+    if steps.is_err() {
+        return Err(steps.unwrap_err())
+    }
+
+    let items = PartItem::from_steps(&steps.unwrap());
+
+    let brand = summary_steps[0].value.strip_suffix("-id").unwrap_or(&summary_steps[0].value);
+    let model = summary_steps[1].value.strip_suffix("-id").unwrap_or(&summary_steps[1].value);
+    let identifier_type = PartRequest::identifier_type(&summary_steps[2].value);
+    let parts = items.iter().enumerate()
+        .map(|(index, item)| format!("{}. {} (archivos adjuntos: {})", index + 1, truncate_description(&item.description), item.attachments.len()))
+        .collect::<Vec<String>>()
+        .join("\n");
+
+    let details = format!(
+        "Marca: {}\nModelo: {}\n{}: {}\nRepuestos:\n{}",
+        brand, model, identifier_type, &summary_steps[2].value, parts
+    );
+    message_request.content.body = Some(message_request.content.body.as_ref().unwrap().replace("{}", &details));
+
+    // Any field edition is finished once the summary is sent again
+    let res = set_tracker_field(&step.tracker_id, "edit_return_status", "");
+
+    if res.is_err() {
+        return Err(res.unwrap_err())
+    }
+
+    message_request.to.push(log.clone().phone_number);
+
+    Ok(message_request)
+}
+
+// Interactive message bodies are limited to 1024 characters, long descriptions are shortened in the summary
+fn truncate_description(description: &str) -> String {
+    if description.chars().count() <= MAX_SUMMARY_DESCRIPTION_LENGTH {
+        return description.to_string()
+    }
+
+    format!("{}...", description.chars().take(MAX_SUMMARY_DESCRIPTION_LENGTH - 3).collect::<String>())
+}
+
+fn summary_reviewed(step: &mut TrackerStep, status: FlowStatus, log:&MessageLog, message_content: &str) -> Result<MessageRequest, String>{
+
+    if message_content == "edit-id" {
+        info!("User requested to edit the request");
+
+        // Update step to send the editable fields instead of accepting the request
+        let edit_status = FlowStatus::EditFieldListSent;
+        step.status = (edit_status as u16).to_string();
+
+        return edit_field_list_sent(step, edit_status, log, message_content)
+    }
+
+    // No required params
+    let mut message_request = status.value().successful_response.unwrap();
+
+    message_request.to.push(log.clone().phone_number);
+
+    Ok(message_request)
+}
+
+fn edit_field_list_sent(step: &TrackerStep, status: FlowStatus, log:&MessageLog, message_content: &str) -> Result<MessageRequest, String>{
+
+    let mut message_request = status.value().successful_response.unwrap();
+
+    let steps = get_all_steps(&step.tracker_id);
+
+    if steps.is_err() {
+        return Err(steps.unwrap_err())
+    }
+
+    // With several parts the user picks the one to correct instead of the whole description
+    let items = PartItem::from_steps(&steps.unwrap());
+    if items.len() > 1 {
+        let choices = &mut message_request.content.list.as_mut().unwrap().choices;
+        choices.retain(|choice| choice.id != "description-field");
+        choices.extend(items.iter().enumerate()
+            .map(|(index, item)| Choice{ id: format!("item-{}", index), value: format!("{}. {}", index + 1, item.description) }));
+    }
+
+    message_request.to.push(log.clone().phone_number);
+
+    Ok(message_request)
+}
+
+fn edit_field_selected(step: &mut TrackerStep, status: FlowStatus, log:&MessageLog, message_content: &str) -> Result<MessageRequest, String>{
+
+    // Step where the edition starts and step after which the flow returns to the summary
+    let (edit_status, return_status) = match message_content {
+        "brand-field" => (FlowStatus::BrandModalSent, FlowStatus::ModelSelected),
+        "model-field" => (FlowStatus::ModelModalSent, FlowStatus::ModelSelected),
+        "identification-field" => (FlowStatus::IdentificationRequestSent, FlowStatus::IdentificationProvided),
+        "description-field" => (FlowStatus::PartDescriptionRequested, FlowStatus::PartDescriptionProvided),
+        item if item.starts_with("item-") => (FlowStatus::PartDescriptionRequested, FlowStatus::PartDescriptionProvided),
+        _ => return Err(format!("Field {} can't be edited", message_content)),
+    };
+
+    info!("Editing request from step {:?}, returning to summary after {:?}", edit_status, return_status);
+    let res = set_tracker_field(&step.tracker_id, "edit_return_status", &(return_status as u16).to_string());
+
+    if res.is_err() {
+        return Err(res.unwrap_err())
+    }
+
+    // Update step to the step where the edition starts
+    step.status = (edit_status as u16).to_string();
+
+    match edit_status {
+        FlowStatus::BrandModalSent => brand_modal_sent(step, edit_status, log, ""),
+        FlowStatus::ModelModalSent => model_modal_sent(step, edit_status, log, ""),
+        FlowStatus::IdentificationRequestSent => identification_request_sent(step, edit_status, log, ""),
+        _ => description_requested(step, edit_status, log, ""),
+    }
+}
+
+fn agent_handoff(step: &TrackerStep, status: FlowStatus, log:&MessageLog, message_content: &str) -> Result<MessageRequest, String>{
+
+    // No required params
+    let mut message_request = status.value().successful_response.unwrap();
+
+    message_request.to.push(log.clone().phone_number);
+
+    Ok(message_request)
+}
+
+fn part_classified(step: &TrackerStep, status: FlowStatus, log:&MessageLog, message_content: &str) -> Result<MessageRequest, String>{
+
+    // Message content holds the classification received from the classification system
+    let classification: Result<PartClassification, serde_json::Error> = serde_json::from_str(message_content);
+
+    if classification.is_err() {
+        return Err(format!("Invalid part classification: {}", classification.unwrap_err()))
+    }
+
+    let classification = classification.unwrap();
+    let mut message_request = status.value().successful_response.unwrap();
+
+    let body = message_request.content.body.as_ref().unwrap()
+        .replace("{category}", &classification.category)
+        .replace("{part_number}", classification.part_number.as_deref().unwrap_or("Por confirmar"));
+    message_request.content.body = Some(body);
+
+    message_request.to.push(log.clone().phone_number);
+
+    Ok(message_request)
+}
+
+fn quotes_sent(step: &mut TrackerStep, status: FlowStatus, log:&MessageLog, message_content: &str) -> Result<MessageRequest, String>{
+
+    let quotes = get_quotes(&step.tracker_id);
+
+    if quotes.is_err() {
+        return Err(quotes.unwrap_err())
+    }
+
+    // Lists support up to 10 choices, only the cheapest quotes are offered
+    let quotes: Vec<SupplierQuote> = quotes.unwrap().into_iter().take(10).collect();
+
+    if quotes.is_empty() {
+        return Err(format!("Tracker {} doesnt have quotes", &step.tracker_id))
+    }
+
+    let mut message_request = status.value().successful_response.unwrap();
+
+    let details = quotes.iter().enumerate()
+        .map(|(index, quote)| format!("{}. {}: ${} - {} - entrega en {} dias", index + 1, quote.supplier, quote.price, quote.condition, quote.delivery_days))
+        .collect::<Vec<String>>()
+        .join("\n");
+    message_request.content.body = Some(message_request.content.body.as_ref().unwrap().replace("{}", &details));
+
+    // Row titles are limited to 24 characters
+    message_request.content.list.as_mut().unwrap().choices = quotes.iter().enumerate()
+        .map(|(index, quote)| Choice{ id: format!("quote-{}", quote.id), value: format!("{}. ${} {}", index + 1, quote.price, quote.supplier).chars().take(24).collect() })
+        .collect();
+
+    // Offered quotes are kept in the step
+    step.value = quotes.iter().map(|quote| quote.id.clone()).collect::<Vec<String>>().join(",");
+
+    message_request.to.push(log.clone().phone_number);
+
+    Ok(message_request)
+}
+
+fn quote_selected(step: &TrackerStep, status: FlowStatus, log:&MessageLog, message_content: &str) -> Result<MessageRequest, String>{
+
+    let quote_id = message_content.strip_prefix("quote-").unwrap_or(message_content);
+    let quote = get_quote(&step.tracker_id, quote_id);
+
+    if quote.is_err() {
+        return Err(quote.unwrap_err())
+    }
+
+    let quote = quote.unwrap();
+
+    let timestamp = match SystemTime::now().duration_since(UNIX_EPOCH) {
+        Ok(n) => n.as_millis().to_string(),
+        Err(_) => panic!("SystemTime before UNIX EPOCH!"),
+    };
+
+    // Let the supplier facing system know which quote was picked
+    let supplier_system = std::env::var("SUPPLIER_SYSTEM_ID").unwrap_or(SUPPLIER_SYSTEM_ID.to_string());
+    let selected_event = QuoteSelectedEvent{
+        event_type: "QuoteSelected".to_string(),
+        event_id: Uuid::new_v4().to_string(),
+        log: MessageLog{
+            timestamp,
+            destination_systems: vec![supplier_system],
+            origin_system: SYSTEM_ID.to_string(),
+            phone_number: log.phone_number.clone(),
+            origin: "OUTGOING".to_string(),
+            register_id: log.register_id.clone(),
+        },
+        tracker_id: step.tracker_id.clone(),
+        quote: quote.clone(),
+    };
+
+    info!("Publishing quote {} selection for tracker {}", &quote.id, &step.tracker_id);
+    let res = publish_message(&selected_event, &log.phone_number, &WorkflowEvent::new("QuoteSelected", &step.tracker_id, &step.id));
+
+    if res.is_err() {
+        return Err(format!("Error publishing quote selection: {}", res.unwrap_err()))
+    }
+
+    // Conversation is over, user goes back to the mode selection
+    let res = reset_user_mode(&log.phone_number);
+
+    if res.is_err() {
+        error!("Failed to reset user mode");
+        return Err(res.unwrap_err())
+    }
+
+    let mut message_request = status.value().successful_response.unwrap();
+    message_request.content.body = Some(message_request.content.body.as_ref().unwrap().replace("{}", &quote.supplier));
+
+    message_request.to.push(log.clone().phone_number);
+
+    Ok(message_request)
+}
+
+fn garage_list_sent(step: &TrackerStep, status: FlowStatus, log:&MessageLog, message_content: &str) -> Result<MessageRequest, String>{
+
+    let profile = get_customer_profile(&log.phone_number);
+
+    if profile.is_err() {
+        return Err(profile.unwrap_err())
+    }
+
+    let mut message_request = status.value().successful_response.unwrap();
+
+    let mut choices: Vec<Choice> = profile.unwrap().vehicles.iter()
+        .map(|vehicle| Choice{ id: vehicle.choice_id(), value: vehicle.choice_title() })
+        .collect();
+    choices.push(Choice{ id: "other-vehicle".to_string(), value: "Otro vehiculo".to_string() });
+    message_request.content.list.as_mut().unwrap().choices = choices;
+
+    message_request.to.push(log.clone().phone_number);
+
+    Ok(message_request)
+}
+
+fn garage_vehicle_selected(step: &mut TrackerStep, status: FlowStatus, log:&MessageLog, message_content: &str) -> Result<MessageRequest, String>{
+
+    if message_content == "other-vehicle" {
+        info!("User chose another vehicle, sending brand list");
+
+        // Update step to continue the flow from the brand selection
+        let brand_status = FlowStatus::BrandModalSent;
+        step.status = (brand_status as u16).to_string();
+
+        return brand_modal_sent(step, brand_status, log, message_content)
+    }
+
+    let profile = get_customer_profile(&log.phone_number);
+
+    if profile.is_err() {
+        return Err(profile.unwrap_err())
+    }
+
+    let identifier = message_content.strip_prefix("vehicle-").unwrap_or_default();
+    let vehicle = profile.unwrap().vehicles.into_iter().find(|vehicle| vehicle.identifier.eq_ignore_ascii_case(identifier));
+
+    if vehicle.is_none() {
+        return Err(format!("Saved vehicle {} not found", message_content))
+    }
+
+    let vehicle = vehicle.unwrap();
+
+    // Vehicle steps are filled with the saved values, so the summary and the part request are built as usual
+    info!("Filling vehicle steps of tracker {} from saved vehicle", &step.tracker_id);
+    let vehicle_steps: Vec<TrackerStep> = [
+        (FlowStatus::BrandSelected, format!("{}-id", vehicle.make)),
+        (FlowStatus::ModelSelected, format!("{}-id", vehicle.model)),
+        (FlowStatus::IdentificationProvided, vehicle.identifier.clone()),
+    ].into_iter().map(|(vehicle_status, value)| TrackerStep{
+        tracker_id: step.tracker_id.clone(),
+        timestamp: step.timestamp.clone(),
+        id: Uuid::new_v4().to_string().replace("-", ""),
+        status: (vehicle_status as u16).to_string(),
+        value,
+        attached_files: "".to_string(),
+        message_reference: step.message_reference.clone(),
+        origin: StepOrigin::System.value().to_string(),
+    }).collect();
+
+    // Steps are written together, a partial vehicle would leave the request without brand or model
+    let res = create_steps(&vehicle_steps);
+
+    if res.is_err() {
+        return Err(format!("Unable to create vehicle steps: {}", res.unwrap_err()))
+    }
+
+    let mut message_request = status.value().successful_response.unwrap();
+    message_request.content.body = Some(message_request.content.body.as_ref().unwrap().replace("{}", &format!("{} {} ({})", vehicle.make, vehicle.model, vehicle.identifier)));
+
+    message_request.to.push(log.clone().phone_number);
+
+    Ok(message_request)
+}
+
+fn add_part_prompt_sent(step: &mut TrackerStep, status: FlowStatus, log:&MessageLog, message_content: &str) -> Result<MessageRequest, String>{
+
+    let steps = get_all_steps(&step.tracker_id);
+
+    if steps.is_err() {
+        return Err(steps.unwrap_err())
+    }
+
+    if PartItem::from_steps(&steps.unwrap()).len() >= MAX_PART_ITEMS {
+        info!("Tracker {} reached the maximum parts, sending summary", &step.tracker_id);
+
+        // Update step to send the summary instead of offering another part
+        let summary_status = FlowStatus::RequestSummarySent;
+        step.status = (summary_status as u16).to_string();
+
+        return request_summary_sent(step, summary_status, log, message_content)
+    }
+
+    // No required params
+    let mut message_request = status.value().successful_response.unwrap();
+
+    message_request.to.push(log.clone().phone_number);
+
+    Ok(message_request)
+}
+
+fn add_part_selected(step: &mut TrackerStep, status: FlowStatus, log:&MessageLog, message_content: &str) -> Result<MessageRequest, String>{
+
+    if message_content == "add-part-id" {
+        info!("User requested another part, requesting its description");
+
+        // Update step to request the description of the next part
+        let description_status = FlowStatus::PartDescriptionRequested;
+        step.status = (description_status as u16).to_string();
+
+        return description_requested(step, description_status, log, message_content)
+    }
+
+    // Update step to send the summary with every part provided
+    let summary_status = FlowStatus::RequestSummarySent;
+    step.status = (summary_status as u16).to_string();
+
+    request_summary_sent(step, summary_status, log, message_content)
+}
@@ -2,7 +2,7 @@ use std::collections::HashMap;
 use fizzy_commons::shared_structs::MessageRequest;
 use redis::Value;
 use serde::{Deserialize, Serialize};
-use crate::constants::{FlowStatusId, MessageType, ResponseStatus};
+use crate::constants::{FlowStatus, FlowStatusId, MessageType, ResponseStatus, VIN_LENGTH};
 
 #[derive(Serialize, Deserialize)]
 pub struct Event {
@@ -43,11 +43,18 @@ pub struct ChangeValue {
 
 #[derive(Serialize, Deserialize)]
 pub struct Status {
-    id: String,
-    status: String,
-    timestamp: String,
-    recipient_id: String,
+    pub(crate) id: String,
+    pub(crate) status: String,
+    pub(crate) timestamp: String,
+    pub(crate) recipient_id: String,
     conversation: Option<Conversation>,
+    pub(crate) errors: Option<Vec<StatusError>>, // Only sent with failed status
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct StatusError {
+    pub(crate) code: i64,
+    pub(crate) title: String,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -123,6 +130,38 @@ pub struct Context {
     id: String,
 }
 
+impl Event {
+    // Name of the WhatsApp profile that sent the event, if provided
+    pub fn contact_name(&self) -> Option<String> {
+        self.entry.iter()
+            .flat_map(|entry| entry.changes.iter())
+            .flat_map(|change| change.value.contacts.iter().flatten())
+            .map(|contact| contact.profile.name.clone())
+            .next()
+    }
+
+    // Every message in the event, in the order they were sent
+    pub fn messages(&self) -> Vec<&Message> {
+        self.entry.iter()
+            .flat_map(|entry| entry.changes.iter())
+            .flat_map(|change| change.value.messages.iter().flatten())
+            .collect()
+    }
+
+    // Delivery status updates for sent messages
+    pub fn statuses(&self) -> Vec<&Status> {
+        self.entry.iter()
+            .flat_map(|entry| entry.changes.iter())
+            .flat_map(|change| change.value.statuses.iter().flatten())
+            .collect()
+    }
+
+    // Message with the given id, none if the event doesn't hold it
+    pub fn find_message(&self, message_id: &str) -> Option<&Message> {
+        self.messages().into_iter().find(|message| message.id == message_id)
+    }
+}
+
 #[derive(Serialize, Deserialize)]
 pub struct Profile {
     name: String,
@@ -168,10 +207,133 @@ pub struct MessageLog {
     pub register_id: String,
 }
 
+// Message waiting to be delivered by the outbox dispatcher
+#[derive(Serialize, Deserialize)]
+pub struct OutboxEntry {
+    pub id: String,
+    pub tracker_id: String,
+    pub step_id: String,
+    pub message: MessageRequest,
+    pub notification: Option<MessageLog>, // Published once the message is sent, with the message reference as register id
+    pub attempts: u32,
+    pub created: u64,
+    pub last_error: Option<String>,
+    #[serde(default)]
+    pub delivery_retries: u32, // Times the message was sent again after WhatsApp reported it failed
+    #[serde(default)]
+    pub template: Option<MessageTemplate>,
+}
+
+impl OutboxEntry {
+    pub fn new(step: &TrackerStep, message: MessageRequest, notification: Option<MessageLog>) -> OutboxEntry {
+        OutboxEntry {
+            id: uuid::Uuid::new_v4().to_string().replace("-", ""),
+            tracker_id: step.tracker_id.clone(),
+            step_id: step.id.clone(),
+            message,
+            notification,
+            attempts: 0,
+            created: step.timestamp.parse::<u64>().unwrap_or(0),
+            last_error: None,
+            delivery_retries: 0,
+            template: FlowStatus::get_from_value(&step.status).value().template,
+        }
+    }
+}
+
+// Delivery of a message sent for a tracker step, updated with the webhook statuses
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct MessageDelivery {
+    pub reference: String,
+    pub tracker_id: String,
+    pub step_id: String,
+    pub phone_number: String,
+    pub status: String, // accepted, sent, delivered, read or failed
+    pub accepted_at: Option<u64>,
+    pub sent_at: Option<u64>,
+    pub delivered_at: Option<u64>,
+    pub read_at: Option<u64>,
+    pub failed_at: Option<u64>,
+    pub error: Option<String>,
+}
+
+impl MessageDelivery {
+    pub fn from_fields(reference: &str, fields: &HashMap<String, String>) -> MessageDelivery {
+        let field = |name: &str| fields.get(name).cloned().unwrap_or_default();
+        let timestamp = |name: &str| fields.get(name).and_then(|value| value.parse::<u64>().ok());
+
+        let mut delivery = MessageDelivery {
+            reference: reference.to_string(),
+            tracker_id: field("tracker_id"),
+            step_id: field("step_id"),
+            phone_number: field("phone_number"),
+            status: "".to_string(),
+            accepted_at: timestamp("accepted_at"),
+            sent_at: timestamp("sent_at"),
+            delivered_at: timestamp("delivered_at"),
+            read_at: timestamp("read_at"),
+            failed_at: timestamp("failed_at"),
+            error: fields.get("error").cloned(),
+        };
+
+        // Statuses can arrive out of order, the furthest one reached is the current status
+        delivery.status = if delivery.failed_at.is_some() {
+            "failed"
+        } else if delivery.read_at.is_some() {
+            "read"
+        } else if delivery.delivered_at.is_some() {
+            "delivered"
+        } else if delivery.sent_at.is_some() {
+            "sent"
+        } else {
+            "accepted"
+        }.to_string();
+
+        delivery
+    }
+}
+
+#[derive(Deserialize)]
+pub struct OutboxParams {
+    pub limit: Option<usize>,
+    pub cursor: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct OutboxPage {
+    pub entries: Vec<OutboxEntry>,
+    pub total: u64,
+    pub next_cursor: Option<String>,
+}
+
+// Stream fields of a published message, so consumer groups can filter events without parsing the payload
+#[derive(Clone, Debug)]
+pub struct WorkflowEvent {
+    pub event_type: String,
+    pub tracker_id: String,
+    pub step_id: String,
+}
+
+impl WorkflowEvent {
+    pub fn new(event_type: &str, tracker_id: &str, step_id: &str) -> WorkflowEvent {
+        WorkflowEvent {
+            event_type: event_type.to_string(),
+            tracker_id: tracker_id.to_string(),
+            step_id: step_id.to_string(),
+        }
+    }
+}
+
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct StandardResponse {
     pub references: Vec<ModifiedReference>,
     pub errors: Option<Vec<String>>,
+
+    #[serde(skip)]
+    pub not_found: bool, // Requested resource doesn't exist, answered as 404
+
+    #[serde(skip)]
+    pub conflict: bool, // Resource isn't in a state that allows the request, answered as 409
 }
 
 impl StandardResponse {
@@ -179,6 +341,8 @@ impl StandardResponse {
         StandardResponse {
             references: vec![],
             errors: None,
+            not_found: false,
+            conflict: false,
         }
     }
 }
@@ -193,7 +357,16 @@ pub struct ModifiedReference {
 pub struct RequestTracker{
     pub(crate) phone_number: String,
     pub(crate) timestamp: String,
-    pub(crate) id: String
+    pub(crate) id: String,
+    pub(crate) edit_return_status: String, // Status after which the flow goes back to the summary, empty if not editing
+    pub(crate) state: String,
+    pub(crate) reminder_sent: bool, // Inactivity reminder was sent since the last step
+    pub(crate) resume_prompt_sent: bool, // User was asked to continue or restart this tracker
+    pub(crate) status: String, // Status of the last step
+    pub(crate) updated: String, // Timestamp of the last step
+    pub(crate) brand: String,
+    pub(crate) model: String,
+    pub(crate) ticket_number: String, // Number shown to customers, e.g. SOL-000123
 
 }
 
@@ -206,6 +379,7 @@ pub struct TrackerStep{
     pub(crate) value: String,
     pub(crate) attached_files: String,
     pub(crate) message_reference: String,
+    pub(crate) origin: String, // Who generated the step: user, system or agent
 }
 
 // #[derive(Serialize, Deserialize, Clone)]
@@ -237,71 +411,417 @@ pub struct ButtonMessage {
 
 pub struct StepDefinition {
     // Requirements to create this step
-    pub(crate) required_response: Option<MessageType>, // Required response type in order to create a step
+    pub(crate) required_response: Option<Vec<MessageType>>, // Response types accepted in order to create a step
     pub(crate) validation_regex: Option<String>, // Required body regex in order to create a step
 
     // Behaviour in case the step can be created
     pub(crate) next_step: Option<FlowStatusId>, // Next step depending on this step definition
     pub(crate) successful_response: Option<MessageRequest>, // Response to user in case the step can be created
     pub(crate) data_origin: Option<String>, // Origin of redis data for lists and button replies
+    pub(crate) template: Option<MessageTemplate>, // Sent instead of the response outside the customer service window
 }
 
+// Pre-approved WhatsApp template, carried as JSON in the body of template messages
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct MessageTemplate {
+    pub name: String,
+    pub language: String,
+    pub parameters: Vec<String>, // Body parameters, {body} is replaced with the body of the replaced message
+}
+
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct OutgoingMessage{
+    pub timestamp: u64,
+    pub reference: String, // Reference returned by the message sender
+    #[serde(default)]
+    pub tracker_id: String, // Empty for messages saved before they were linked to a tracker
+    pub message: serde_json::Value,
+}
+
+#[derive(Serialize, Clone, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum TranscriptEntry{
+    Incoming{
+        timestamp: u64,
+        message_id: String,
+        message_type: String,
+        content: String,
+    },
+    Outgoing{
+        timestamp: u64,
+        reference: String,
+        message: serde_json::Value,
+        delivery: Option<MessageDelivery>,
+    },
+    Step{
+        timestamp: u64,
+        step_id: String,
+        status: String,
+        status_name: String,
+        value: String,
+        origin: String,
+    },
+}
+
+impl TranscriptEntry {
+    pub fn timestamp(&self) -> u64 {
+        match self {
+            TranscriptEntry::Incoming { timestamp, .. } => *timestamp,
+            TranscriptEntry::Outgoing { timestamp, .. } => *timestamp,
+            TranscriptEntry::Step { timestamp, .. } => *timestamp,
+        }
+    }
+}
+
+// Customer details kept across requests, updated with every message received
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct CustomerProfile {
+    pub phone_number: String,
+    pub name: Option<String>,
+    pub first_seen: u64,
+    pub last_seen: u64,
+    pub requests: u32,
+    pub language: String,
+    pub vehicles: Vec<SavedVehicle>,
+}
+
+impl CustomerProfile {
+    pub fn from_fields(phone_number: &str, fields: &HashMap<String, String>) -> CustomerProfile {
+        let number = |name: &str| fields.get(name).and_then(|value| value.parse::<u64>().ok()).unwrap_or(0);
+
+        CustomerProfile {
+            phone_number: phone_number.to_string(),
+            name: fields.get("name").cloned(),
+            first_seen: number("first_seen"),
+            last_seen: number("last_seen"),
+            requests: number("requests") as u32,
+            language: fields.get("language").cloned().unwrap_or_default(),
+            vehicles: fields.get("vehicles").and_then(|vehicles| serde_json::from_str(vehicles).ok()).unwrap_or_default(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct SavedVehicle {
+    pub make: String,
+    pub model: String,
+    pub identifier: String, // VIN or plate
+}
+
+// Lists support up to 10 choices, one of them is kept for other vehicles
+pub const MAX_SAVED_VEHICLES: usize = 9;
+
+impl SavedVehicle {
+    // Adds the vehicle as the most recent one, replacing it if it was already saved
+    pub fn add_to(self, vehicles: &mut Vec<SavedVehicle>) {
+        vehicles.retain(|vehicle| !vehicle.identifier.eq_ignore_ascii_case(&self.identifier));
+        vehicles.insert(0, self);
+        vehicles.truncate(MAX_SAVED_VEHICLES);
+    }
+
+    // Row id of the vehicle in the saved vehicles list, the saved vehicles may change before the user answers
+    pub fn choice_id(&self) -> String {
+        format!("vehicle-{}", self.identifier)
+    }
+
+    // Choice shown in the saved vehicles list, row titles are limited to 24 characters
+    pub fn choice_title(&self) -> String {
+        format!("{} {} {}", self.make, self.model, self.identifier).chars().take(24).collect()
+    }
+}
+
+// Message received through any channel, as handled by the flow
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct InboundMessage {
+    pub id: String,
+    pub user_id: String, // Phone number, or the channel prefixed user for other channels
+    pub message_type: MessageType,
+    pub channel_type: String, // Type as named by the channel, e.g. text or interactive
+    pub content: String,
+    pub image_id: Option<String>, // WhatsApp media id of the attached image
+    pub timestamp: u64,
+    #[serde(default)]
+    pub contact_name: Option<String>, // Profile name of the user, when the channel provides it
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct TrackerSummary{
+    pub id: String,
+    pub ticket_number: String,
+    pub phone_number: String,
+    pub created: String,
+    pub updated: String,
+    pub status: String,
+    pub status_name: String,
+    pub state: String,
+    pub brand: String,
+    pub model: String,
+}
+
+impl From<&RequestTracker> for TrackerSummary {
+    fn from(tracker: &RequestTracker) -> Self {
+        TrackerSummary{
+            id: tracker.id.clone(),
+            ticket_number: tracker.ticket_number.clone(),
+            phone_number: tracker.phone_number.clone(),
+            created: tracker.timestamp.clone(),
+            updated: tracker.updated.clone(),
+            status: tracker.status.clone(),
+            status_name: if tracker.status.is_empty() { "".to_string() } else { FlowStatus::get_from_value(&tracker.status).name() },
+            state: tracker.state.clone(),
+            brand: tracker.brand.clone(),
+            model: tracker.model.clone(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct TrackerPage{
+    pub trackers: Vec<TrackerSummary>,
+    pub total: u64,
+    pub next_cursor: Option<String>, // Offset of the next page, none when there are no more trackers
+}
+
+#[derive(Deserialize)]
+pub struct TrackerSearchParams{
+    pub phone: Option<String>,
+    pub status: Option<String>, // Status id or name
+    pub state: Option<String>,
+    pub brand: Option<String>,
+    pub model: Option<String>,
+    pub ticket: Option<String>,
+    pub created_from: Option<u64>,
+    pub created_to: Option<u64>,
+    pub sort: Option<String>, // created or updated
+    pub order: Option<String>, // asc or desc
+    pub limit: Option<usize>,
+    pub cursor: Option<String>,
+}
 
 #[derive(Deserialize)]
 pub struct TrackerParam{
-    pub tracker_id: String
+    pub tracker_id: String,
+    pub limit: Option<usize>, // All steps are returned if not specified
+    pub cursor: Option<String>,
 }
 
-impl TrackerStep {
-    pub fn parse_from_redis(&mut self, register: &Vec<Value>) -> TrackerStep {
-        let mut values: HashMap<String, String> = HashMap::new();
+#[derive(Serialize, Clone, Debug)]
+pub struct TrackerStepView{
+    #[serde(flatten)]
+    pub step: TrackerStep,
+    pub status_name: String,
+}
 
+#[derive(Serialize, Clone, Debug)]
+#[serde(untagged)]
+pub enum TrackerStepsResponse{
+    All(Vec<TrackerStepView>),
+    Page(TrackerStepsPage),
+}
 
-        // Parse bulk into key-val hashmap
-        let mut param_name = "".to_string();
-        for (index, elem) in register.iter().enumerate(){
+#[derive(Serialize, Clone, Debug)]
+pub struct TrackerStepsPage{
+    pub steps: Vec<TrackerStepView>,
+    pub next_cursor: Option<String>, // Offset of the next page, none when there are no more steps
+}
 
+#[derive(Deserialize)]
+pub struct AgentReply{
+    pub message: Option<String>,
+    pub media_url: Option<String>,
+    pub media_type: Option<String>, // image, document, audio or video, defaults to image
+}
 
-            let string_val = match elem {
-                Value::Data(val) => {
-                    String::from_utf8(val.clone())
-                }
-                _ => {
-                    panic!("Unexpected value")
-                }
-            }.unwrap();
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct PartClassification{
+    pub category: String,
+    pub part_number: Option<String>,
+}
 
-            if index % 2 == 0 {
-                param_name = string_val;
-            }else{
-                values.insert(param_name.to_string(), string_val);
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct SupplierQuote{
+    #[serde(default)]
+    pub id: String, // Assigned when the quote is attached to the tracker
+    pub supplier: String,
+    pub price: u64,
+    pub condition: String, // new or used
+    pub delivery_days: u16,
+    pub photo_url: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct QuotesRequest{
+    pub quotes: Vec<SupplierQuote>,
+}
+
+// Event sent to the supplier facing system once the customer picks a quote
+#[derive(Serialize, Deserialize, Clone)]
+pub struct QuoteSelectedEvent{
+    pub event_type: String, // Always QuoteSelected
+    pub event_id: String,
+    #[serde(flatten)]
+    pub log: MessageLog,
+    pub tracker_id: String,
+    pub quote: SupplierQuote,
+}
+
+#[derive(Deserialize)]
+pub struct HandoffRelease{
+    pub status: u16 // Flow status from which the bot continues the conversation
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct AgentNotification{
+    pub tracker_id: String,
+    pub phone_number: String,
+    pub timestamp: String,
+    pub event: String, // HANDOFF_REQUESTED, USER_MESSAGE, DELIVERY_FAILED or OUTBOX_DEAD_LETTER
+    pub content: String,
+}
+
+// Part request as gathered by the flow, for systems that shouldn't need to replay the tracker steps
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct PartRequest{
+    pub tracker_id: String,
+    pub ticket_number: String,
+    pub phone_number: String,
+    pub contact_name: Option<String>,
+    pub make: Option<String>,
+    pub model: Option<String>,
+    pub identifier_type: Option<String>, // VIN or Patente
+    pub identifier: Option<String>,
+    pub description: Option<String>, // First item, kept for consumers handling a single part
+    pub attachments: Vec<String>,
+    pub items: Vec<PartItem>,
+    pub created_at: u64,
+    pub updated_at: u64,
+    pub accepted_at: Option<u64>,
+    pub status: String, // Name of the last flow status
+    pub state: String,
+}
+
+impl PartRequest {
+    // Builds the request from the tracker steps ordered by creation, latest value of each field wins
+    pub fn from_steps(tracker: &RequestTracker, steps: &[TrackerStep], contact_name: Option<String>) -> PartRequest {
+        // Status isn't set on trackers without steps, it falls back to the last step if there's any
+        let status = if tracker.status.is_empty() {
+            steps.last().map(|step| step.status.clone()).unwrap_or_default()
+        } else {
+            tracker.status.clone()
+        };
+
+        let mut part_request = PartRequest{
+            tracker_id: tracker.id.clone(),
+            ticket_number: tracker.ticket_number.clone(),
+            phone_number: tracker.phone_number.clone(),
+            contact_name,
+            make: None,
+            model: None,
+            identifier_type: None,
+            identifier: None,
+            description: None,
+            attachments: vec![],
+            items: PartItem::from_steps(steps),
+            created_at: tracker.timestamp.parse::<u64>().unwrap_or(0),
+            updated_at: tracker.updated.parse::<u64>().unwrap_or(0),
+            accepted_at: None,
+            status: if status.is_empty() { "".to_string() } else { FlowStatus::get_from_value(&status).name() },
+            state: tracker.state.clone(),
+        };
+
+        for step in steps {
+            let value = step.value.strip_suffix("-id").unwrap_or(&step.value).to_string();
+
+            match FlowStatus::get_from_value(&step.status) {
+                FlowStatus::BrandSelected => part_request.make = Some(value),
+                FlowStatus::ModelSelected => part_request.model = Some(value),
+                FlowStatus::IdentificationProvided => {
+                    part_request.identifier_type = Some(PartRequest::identifier_type(&step.value).to_string());
+                    part_request.identifier = Some(step.value.clone());
+                }
+                FlowStatus::RequestAccepted => part_request.accepted_at = step.timestamp.parse::<u64>().ok(),
+                _ => {}
             }
         }
 
-        // Add parsed values to struct
-        self.value = String::from(values.get("value").expect("Expected parameter value wasn't found"));
-        values.remove("value");
-        self.status = String::from(values.get("status").expect("Expected parameter status wasn't found"));
-        values.remove("status");
-        self.tracker_id = String::from(values.get("tracker_id").expect("Expected parameter tracker_id wasn't found"));
-        values.remove("tracker_id");
-        self.timestamp = String::from(values.get("timestamp").expect("Expected parameter timestamp wasn't found"));
-        values.remove("timestamp");
-        self.attached_files = String::from(values.get("attached_files").expect("Expected parameter attached_files wasn't found"));
-        values.remove("attached_files");
-        self.message_reference = String::from(values.get("message_reference").expect("Expected parameter message_reference wasn't found"));
-        values.remove("message_reference");
-
-
-        // Fails it there are values in the hashmap that are not parsed into the tracker step struct
-        if values.iter().len() > 0 {
-            panic!("Found more values than expected");
+        if let Some(item) = part_request.items.first() {
+            part_request.description = Some(item.description.clone());
+            part_request.attachments = item.attachments.clone();
         }
 
-        self.clone()
+        if part_request.updated_at == 0 {
+            part_request.updated_at = steps.last().and_then(|step| step.timestamp.parse::<u64>().ok()).unwrap_or(part_request.created_at);
+        }
+
+        part_request
+    }
+
+    // VINs have 17 characters, anything else is taken as a license plate
+    pub fn identifier_type(identifier: &str) -> &'static str {
+        if identifier.len() == VIN_LENGTH { "VIN" } else { "Patente" }
+    }
+}
+
+// Lists support up to 10 rows, the edit list holds one per part along with the vehicle fields
+pub const MAX_PART_ITEMS: usize = 7;
+
+// Part requested within a tracker, each description provided adds one
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct PartItem{
+    pub description: String,
+    pub attachments: Vec<String>,
+}
+
+impl PartItem {
+    // Items of the tracker steps ordered by creation. Editing the description starts the items again,
+    // editing a single item replaces it with the next description provided
+    pub fn from_steps(steps: &[TrackerStep]) -> Vec<PartItem> {
+        let mut items: Vec<PartItem> = vec![];
+        let mut replaced: Option<usize> = None;
+
+        for step in steps {
+            match FlowStatus::get_from_value(&step.status) {
+                FlowStatus::PartDescriptionRequested => {
+                    if step.value == "description-field" {
+                        items.clear();
+                    }
+
+                    replaced = step.value.strip_prefix("item-").and_then(|index| index.parse::<usize>().ok());
+                }
+                FlowStatus::PartDescriptionProvided => {
+                    let item = PartItem{
+                        description: step.value.clone(),
+                        attachments: step.attached_files.split(",")
+                            .filter(|file| !file.is_empty())
+                            .map(|file| file.to_string())
+                            .collect(),
+                    };
+
+                    match replaced.take() {
+                        Some(index) if index < items.len() => items[index] = item,
+                        _ => items.push(item),
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        items
     }
 }
 
+// Event sent to the classification system once the user accepts the request, schema documented in the README
+#[derive(Serialize, Deserialize, Clone)]
+pub struct PartRequestSubmitted{
+    pub event_type: String, // Always PartRequestSubmitted
+    pub version: u16,
+    pub event_id: String,
+    #[serde(flatten)]
+    pub log: MessageLog,
+    pub part_request: PartRequest,
+}
+
 impl Default for TrackerStep{
     fn default() -> Self {
         TrackerStep{
@@ -312,6 +832,173 @@ impl Default for TrackerStep{
             value: "".to_string(),
             attached_files: "".to_string(),
             message_reference: "".to_string(),
+            origin: "".to_string(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tools::get_message_content;
+
+    fn step(status: u16, value: &str, attached_files: &str, timestamp: &str) -> TrackerStep {
+        TrackerStep{
+            tracker_id: "tracker".to_string(),
+            timestamp: timestamp.to_string(),
+            status: status.to_string(),
+            value: value.to_string(),
+            attached_files: attached_files.to_string(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn part_request_uses_latest_step_values() {
+        let tracker = RequestTracker{
+            phone_number: "56912345678".to_string(),
+            timestamp: "1000".to_string(),
+            id: "tracker".to_string(),
+            edit_return_status: "".to_string(),
+            state: "completed".to_string(),
+            reminder_sent: false,
+            resume_prompt_sent: false,
+            status: "10".to_string(),
+            updated: "9000".to_string(),
+            brand: "toyota".to_string(),
+            model: "yaris".to_string(),
+            ticket_number: "SOL-000001".to_string(),
+        };
+
+        let steps = vec![
+            step(3, "nissan-id", "", "2000"),
+            step(5, "versa-id", "", "3000"),
+            step(7, "ABCD12", "", "4000"),
+            step(9, "Pastillas de freno", "photo-1,photo-2", "5000"),
+            step(3, "toyota-id", "", "6000"),
+            step(5, "yaris-id", "", "7000"),
+            step(7, "JTDBT923771234567", "", "8000"),
+            step(10, "confirm-id", "", "9000"),
+        ];
+
+        let part_request = PartRequest::from_steps(&tracker, &steps, Some("Juan".to_string()));
+
+        assert_eq!(part_request.make, Some("toyota".to_string()));
+        assert_eq!(part_request.model, Some("yaris".to_string()));
+        assert_eq!(part_request.identifier_type, Some("VIN".to_string()));
+        assert_eq!(part_request.identifier, Some("JTDBT923771234567".to_string()));
+        assert_eq!(part_request.description, Some("Pastillas de freno".to_string()));
+        assert_eq!(part_request.attachments, vec!["photo-1".to_string(), "photo-2".to_string()]);
+        assert_eq!(part_request.accepted_at, Some(9000));
+        assert_eq!(part_request.status, "RequestAccepted");
+        assert_eq!(part_request.contact_name, Some("Juan".to_string()));
+
+        // Trackers without status use the last step or are left without one
+        let tracker = RequestTracker{ status: "".to_string(), ..tracker };
+        assert_eq!(PartRequest::from_steps(&tracker, &steps, None).status, "RequestAccepted");
+        assert_eq!(PartRequest::from_steps(&tracker, &[], None).status, "");
+    }
+
+    #[test]
+    fn event_iterates_batched_messages_and_statuses() {
+        let event: Event = serde_json::from_str(r#"{
+            "object": "whatsapp_business_account",
+            "entry": [{
+                "id": "1",
+                "changes": [
+                    {"field": "messages", "value": {
+                        "messaging_product": "whatsapp",
+                        "metadata": {"display_phone_number": "56900000000", "phone_number_id": "2"},
+                        "messages": [
+                            {"from": "56912345678", "id": "wamid.1", "timestamp": "1", "type": "text", "text": {"body": "Hola"}},
+                            {"from": "56912345678", "id": "wamid.2", "timestamp": "2", "type": "sticker"}
+                        ]
+                    }},
+                    {"field": "messages", "value": {
+                        "messaging_product": "whatsapp",
+                        "metadata": {"display_phone_number": "56900000000", "phone_number_id": "2"},
+                        "statuses": [{"id": "wamid.0", "status": "delivered", "timestamp": "3", "recipient_id": "56912345678"}]
+                    }}
+                ]
+            }]
+        }"#).unwrap();
+
+        let ids: Vec<&str> = event.messages().iter().map(|message| message.id.as_str()).collect();
+        assert_eq!(ids, vec!["wamid.1", "wamid.2"]);
+        assert_eq!(event.statuses()[0].status, "delivered");
+
+        assert_eq!(get_message_content(event.find_message("wamid.2").unwrap()), "");
+        assert!(event.find_message("wamid.3").is_none());
+    }
+
+    #[test]
+    fn delivery_status_is_furthest_reached() {
+        let mut fields = HashMap::new();
+        fields.insert("tracker_id".to_string(), "tracker".to_string());
+        fields.insert("accepted_at".to_string(), "1000".to_string());
+        fields.insert("read_at".to_string(), "3000".to_string());
+
+        // Read status arrived before the delivered one
+        let delivery = MessageDelivery::from_fields("wamid.1", &fields);
+        assert_eq!(delivery.status, "read");
+        assert_eq!(delivery.delivered_at, None);
+        assert_eq!(delivery.tracker_id, "tracker");
+
+        fields.insert("failed_at".to_string(), "4000".to_string());
+        assert_eq!(MessageDelivery::from_fields("wamid.1", &fields).status, "failed");
+    }
+
+    #[test]
+    fn saved_vehicles_keep_most_recent_first() {
+        let vehicle = |identifier: &str| SavedVehicle{ make: "Toyota".to_string(), model: "Yaris".to_string(), identifier: identifier.to_string() };
+
+        let mut vehicles = vec![];
+        for index in 0..MAX_SAVED_VEHICLES + 1 {
+            vehicle(&format!("ABC{}", index)).add_to(&mut vehicles);
         }
+        assert_eq!(vehicles.len(), MAX_SAVED_VEHICLES);
+        assert_eq!(vehicles[0].identifier, format!("ABC{}", MAX_SAVED_VEHICLES));
+
+        // Saving it again moves it first instead of duplicating it
+        vehicle("abc5").add_to(&mut vehicles);
+        assert_eq!(vehicles.len(), MAX_SAVED_VEHICLES);
+        assert_eq!(vehicles[0].identifier, "abc5");
+        assert_eq!(vehicles.iter().filter(|saved| saved.identifier.eq_ignore_ascii_case("abc5")).count(), 1);
+    }
+
+    #[test]
+    fn saved_vehicle_choice_passes_selection_regex() {
+        let vehicle = SavedVehicle{ make: "Toyota".to_string(), model: "Yaris".to_string(), identifier: "ABCD12".to_string() };
+        let regex = regex::Regex::new(&FlowStatus::GarageVehicleSelected.value().validation_regex.unwrap()).unwrap();
+
+        assert!(regex.is_match(&vehicle.choice_id()));
+        assert!(regex.is_match("other-vehicle"));
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn part_items_restart_when_description_is_edited() {
+        let steps = vec![
+            step(9, "Pastillas de freno", "photo-1", "1000"),
+            step(8, "add-part-id", "", "2000"),
+            step(9, "Disco de freno", "", "3000"),
+        ];
+
+        let items = PartItem::from_steps(&steps);
+        assert_eq!(items.len(), 2);
+        assert_eq!(items[0].attachments, vec!["photo-1".to_string()]);
+        assert_eq!(items[1].description, "Disco de freno");
+
+        let mut edited = steps.clone();
+        edited.push(step(8, "description-field", "", "4000"));
+        edited.push(step(9, "Filtro de aceite", "", "5000"));
+        assert_eq!(PartItem::from_steps(&edited), vec![PartItem{ description: "Filtro de aceite".to_string(), attachments: vec![] }]);
+
+        let mut item_edited = steps.clone();
+        item_edited.push(step(8, "item-0", "", "4000"));
+        item_edited.push(step(9, "Pastillas de freno delanteras", "", "5000"));
+        let items = PartItem::from_steps(&item_edited);
+        assert_eq!(items.len(), 2);
+        assert_eq!(items[0].description, "Pastillas de freno delanteras");
+        assert_eq!(items[1].description, "Disco de freno");
+    }
+}
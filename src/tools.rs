@@ -4,27 +4,64 @@ use std::collections::HashMap;
 use std::env::Args;
 use std::error::Error;
 use std::process::Command;
+use std::sync::OnceLock;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 use aws_config::SdkConfig;
-use fizzy_commons::shared_structs::MessageRequest;
+use fizzy_commons::shared_structs::{MessageContent, MessageRequest};
 use redis::Commands;
 use uuid::Uuid;
-use crate::constants::MessageType;
+use crate::circuit_breaker::graph_api_breaker;
+use crate::constants::{MessageType, TICKET_PREFIX};
+use crate::redis::{get_customer_profile, get_last_inbound, save_outgoing_message};
 use crate::s3_tools;
+use crate::channel::get_channel;
+
+use crate::structs::{ListChoice, MediaData, Message, MessageTemplate, StandardResponse};
+
+// Numeric setting from the environment, invalid values fall back to the default instead of stopping the service
+pub fn get_env_setting(name: &str, default: u64) -> u64 {
+    match std::env::var(name) {
+        Ok(value) => value.parse().unwrap_or_else(|_| {
+            warn!("{} must be a number, using the default {}", name, default);
+            default
+        }),
+        Err(_) => default,
+    }
+}
+
+// Client shared by upstream calls, so a hung upstream can't hold a worker indefinitely
+pub(crate) fn http_agent() -> &'static ureq::Agent {
+    static AGENT: OnceLock<ureq::Agent> = OnceLock::new();
+
+    AGENT.get_or_init(|| {
+        let connect_timeout = get_env_setting("UPSTREAM_CONNECT_TIMEOUT_MILLIS", 2000);
+        let read_timeout = get_env_setting("UPSTREAM_READ_TIMEOUT_MILLIS", 10000);
+
+        ureq::AgentBuilder::new()
+            .timeout_connect(Duration::from_millis(connect_timeout))
+            .timeout_read(Duration::from_millis(read_timeout))
+            .timeout_write(Duration::from_millis(read_timeout))
+            .build()
+    })
+}
 
-use crate::structs::{Event, ListChoice, MediaData, StandardResponse};
+// Graph API base url, used for media and by the cloud_api sender so both point at the same version
+pub fn graph_api_url() -> String {
+    std::env::var("GRAPH_API_URL").unwrap_or("https://graph.facebook.com/v15.0".to_string())
+}
 
 pub(crate) fn get_media_url(media_id: &str) -> Result<MediaData, Box<dyn Error>> {
-    let resp: String = ureq::get(format!("https://graph.facebook.com/v15.0/{}", media_id).as_str())
-        .set(
-            "Authorization",
-            format!("Bearer {}", std::env::var("META_TOKEN").unwrap()).as_str(),
-        )
-        .call()?
-        .into_string()
-        .unwrap();
-
-    let media_data: MediaData = serde_json::from_str(resp.as_str()).unwrap();
+    let resp = graph_api_breaker().call(|| {
+        http_agent().get(format!("{}/{}", graph_api_url(), media_id).as_str())
+            .set(
+                "Authorization",
+                format!("Bearer {}", std::env::var("META_TOKEN").unwrap()).as_str(),
+            )
+            .call()
+    })?;
+
+    let media_data: MediaData = serde_json::from_str(resp.into_string()?.as_str())?;
     Ok(media_data)
 }
 
@@ -246,87 +283,213 @@ pub async fn upload_image(
 }
 
 
-pub fn get_message_content(event: &Event) -> String {
-    let message = event.entry[0].changes[0].value.messages.as_ref().unwrap();
-    info!("Obtaining message content for type {}", message[0].message_type.as_str());
-    match message[0].message_type.as_str() {
-        "text" => {
-            message[0]
-                .clone()
-                .text
-                .unwrap()
-                .body
-        }
-        "interactive" =>{
-            if message[0].interactive.as_ref().unwrap().button_reply.as_ref().is_some(){
-                &message[0].interactive.as_ref().unwrap().button_reply.as_ref().unwrap().id
-            } else if message[0].interactive.as_ref().unwrap().list_reply.as_ref().is_some(){
-                &message[0].interactive.as_ref().unwrap().list_reply.as_ref().unwrap().id
-            }else{
-                panic!("message type no supported")
-            }.to_string()
-        },
-        "image" => {
-            message[0]
-                .clone()
-                .image
-                .unwrap()
-                .caption
-        }
-        _ => {
-            panic!("Message type not supported")
+pub fn get_message_content(message: &Message) -> String {
+    info!("Obtaining message content for type {}", message.message_type.as_str());
+
+    // Unsupported types have no readable content
+    match message.message_type.as_str() {
+        "text" => message.text.as_ref().map(|text| text.body.clone()).unwrap_or_default(),
+        "interactive" => {
+            let interactive = message.interactive.as_ref();
+            interactive.and_then(|interactive| interactive.button_reply.as_ref().or(interactive.list_reply.as_ref()))
+                .map(|reply| reply.id.clone())
+                .unwrap_or_default()
         }
-    }.to_string()
+        "image" => message.image.as_ref().map(|image| image.caption.clone()).unwrap_or_default(),
+        _ => "".to_string(),
+    }
 }
 
-pub fn find_message_type(event: &Event) -> MessageType {
-    let message = event.entry[0].changes[0].value.messages.as_ref().unwrap();
-    match message[0].message_type.as_str() {
+pub fn find_message_type(message: &Message) -> MessageType {
+    match message.message_type.as_str() {
         "text" => {
             MessageType::PlainText
 
         },
         "interactive" =>{
-            if message[0].interactive.as_ref().unwrap().button_reply.is_some(){
+            let interactive = message.interactive.as_ref();
+
+            if interactive.map(|interactive| interactive.button_reply.is_some()).unwrap_or(false) {
                 MessageType::ButtonSelection
-            } else if message[0].interactive.as_ref().unwrap().list_reply.is_some(){
+            } else if interactive.map(|interactive| interactive.list_reply.is_some()).unwrap_or(false) {
                 MessageType::ListSelection
             }else{
-                panic!("message type no supported")
+                MessageType::Unsupported
             }
         },
         "image" => {
             MessageType::PlainTextAndImage
         }
         _ => {
-            panic!("Message type not supported: {}", message[0].message_type.as_str())
+            info!("Message type not supported: {}", message.message_type.as_str());
+            MessageType::Unsupported
         }
     }
 }
 
-pub fn send_message(message: MessageRequest) -> Result<StandardResponse, String> {
+pub async fn send_message(message: &MessageRequest) -> Result<StandardResponse, String> {
 
     info!("{}", ureq::json!(message));
 
     debug!("Sending message with payload: \n {}", ureq::json!(message));
-    let url = std::env::var("WHATSAPP_MANAGER_HOST").unwrap();
+    let recipient = message.to.get(0).map(|recipient| recipient.as_str()).unwrap_or_default();
+    let channel = get_channel(recipient);
 
-    let resp = ureq::post(format!("{}/message", url).as_str())
-        .send_json(ureq::json!(message)).unwrap()
-        .into_string();
+    debug!("Sending message through {} channel", channel.name());
+    let parsed_response = channel.send(message).await;
 
-    if resp.is_err() {
-        return Err(resp.unwrap_err().to_string())
+    if parsed_response.is_err() {
+        return Err(parsed_response.unwrap_err())
     }
 
-    let parsed_response: StandardResponse = serde_json::from_str(&resp.unwrap()).unwrap();
+    let parsed_response = parsed_response.unwrap();
+
+    // Keep sent messages so they can be included in the tracker transcript
+    let reference = parsed_response.references.get(0).map(|reference| reference.reference.clone()).unwrap_or_default();
+    let save_res = save_outgoing_message(message, &reference);
+
+    if save_res.is_err() {
+        error!("Error saving outgoing message: {}", save_res.unwrap_err());
+    }
 
     Ok(parsed_response)
 }
 
+// Sends the message, replaced by the template when the customer service window is closed
+pub async fn send_message_with_template(message: &MessageRequest, template: Option<&MessageTemplate>) -> Result<StandardResponse, String> {
+    if template.is_some() && !message.to.iter().all(|phone_number| is_within_service_window(phone_number)) {
+        info!("Customer service window closed, sending template {}", template.unwrap().name);
+
+        // Messages are delivered per recipient, the template is sent in the language of their profile
+        let language = message.to.first()
+            .and_then(|phone_number| get_customer_profile(phone_number).ok())
+            .map(|profile| profile.language)
+            .filter(|language| !language.is_empty())
+            .unwrap_or_else(|| template.unwrap().language.clone());
+
+        return send_message(&to_template_message(message, template.unwrap(), &language)).await
+    }
+
+    send_message(message).await
+}
+
+// WhatsApp only accepts free-form messages within the window after the user's last message
+pub fn is_within_service_window(phone_number: &str) -> bool {
+    if !get_channel(phone_number).has_service_window() {
+        return true
+    }
+
+    let window_hours = get_env_setting("CUSTOMER_SERVICE_WINDOW_HOURS", 24);
+
+    let now = match SystemTime::now().duration_since(UNIX_EPOCH) {
+        Ok(n) => n.as_millis() as u64,
+        Err(_) => panic!("SystemTime before UNIX EPOCH!"),
+    };
+
+    match get_last_inbound(phone_number) {
+        Ok(Some(last_inbound)) => now.saturating_sub(last_inbound) < window_hours * 3600 * 1000,
+        Ok(None) => false,
+        Err(err) => {
+            // Free-form message is attempted when the window can't be checked
+            error!("Error checking customer service window: {}", err);
+            true
+        }
+    }
+}
+
+// Template message with the parameters replaced, templates don't accept line breaks in parameters
+pub fn to_template_message(message: &MessageRequest, template: &MessageTemplate, language: &str) -> MessageRequest {
+    let body = message.content.body.clone().unwrap_or_default().replace("\n", " ");
+
+    let template_message = MessageTemplate{
+        name: template.name.clone(),
+        language: language.to_string(),
+        parameters: template.parameters.iter().map(|parameter| parameter.replace("{body}", &body)).collect(),
+    };
+
+    MessageRequest{
+        system_id: message.system_id,
+        to: message.to.clone(),
+        message_type: "template".to_string(),
+        content: MessageContent {
+            body: Some(serde_json::to_string(&template_message).unwrap()),
+            list: None,
+            buttons: None,
+        },
+    }
+}
+
+// Replaces {name} with the customer name, removing it when the name isn't known
+pub fn personalize_message(message: &mut MessageRequest, phone_number: &str) {
+    let body = message.content.body.as_ref();
+
+    if body.is_none() || !body.unwrap().contains("{name}") {
+        return
+    }
+
+    let name = get_customer_profile(phone_number).ok().and_then(|profile| profile.name);
+    message.content.body = Some(replace_name(body.unwrap(), name.as_deref()));
+}
+
+fn replace_name(body: &str, name: Option<&str>) -> String {
+    match name {
+        Some(name) if !name.trim().is_empty() => body.replace("{name}", name.trim()),
+        _ => body.replace(" {name}", "").replace("{name}", ""),
+    }
+}
+
+// Escapes RediSearch special characters so the value can be used inside a query
+pub fn escape_query_value(value: &str) -> String {
+    value.chars()
+        .map(|c| if c.is_alphanumeric() || c == '_' { c.to_string() } else { format!("\\{}", c) })
+        .collect()
+}
+
+// Ticket number for the counter value, padded so tickets are read the same way
+pub fn format_ticket_number(number: u64) -> String {
+    format!("{}{:06}", TICKET_PREFIX, number)
+}
+
+// Ticket number as stored when the value is one, customers may type it in lowercase or without padding
+pub fn parse_ticket_number(value: &str) -> Option<String> {
+    let value = value.trim().to_uppercase();
+    let number = value.strip_prefix(TICKET_PREFIX)?;
+
+    if number.is_empty() || !number.chars().all(|c| c.is_ascii_digit()) {
+        return None
+    }
+
+    number.parse::<u64>().ok().map(format_ticket_number)
+}
+
+// Checks the X-Hub-Signature-256 header(sha256=<hex hmac>) Meta sends along the webhook payload
+pub fn verify_signature(payload: &[u8], signature_header: &str, app_secret: &str) -> bool {
+    let signature = signature_header.strip_prefix("sha256=").and_then(decode_hex);
+
+    if signature.is_none() {
+        return false
+    }
+
+    let key = ring::hmac::Key::new(ring::hmac::HMAC_SHA256, app_secret.as_bytes());
+    ring::hmac::verify(&key, payload, &signature.unwrap()).is_ok()
+}
+
+fn decode_hex(value: &str) -> Option<Vec<u8>> {
+    if value.len() % 2 != 0 {
+        return None
+    }
+
+    (0..value.len())
+        .step_by(2)
+        .map(|index| value.get(index..index + 2).and_then(|byte| u8::from_str_radix(byte, 16).ok()))
+        .collect()
+}
+
 #[cfg(test)]
 mod tests {
-    use crate::tools::validate_vin;
+    use fizzy_commons::shared_structs::{MessageContent, MessageRequest};
+    use crate::structs::MessageTemplate;
+    use crate::tools::{escape_query_value, format_ticket_number, parse_ticket_number, replace_name, to_template_message, validate_vin, verify_signature};
 
     #[test]
     fn validate_vin_ok() {
@@ -339,5 +502,68 @@ mod tests {
         let vin = validate_vin("1111111111111111".to_string());
         assert_eq!(vin, false)
     }
-}
 
+    #[test]
+    fn escape_query_value_special_chars() {
+        let escaped = escape_query_value("mercedes-benz clase a");
+        assert_eq!(escaped, "mercedes\\-benz\\ clase\\ a")
+    }
+
+    #[test]
+    fn escape_query_value_plain() {
+        let escaped = escape_query_value("56912345678");
+        assert_eq!(escaped, "56912345678")
+    }
+
+    #[test]
+    fn ticket_numbers_are_padded_and_parsed() {
+        assert_eq!(format_ticket_number(123), "SOL-000123");
+        assert_eq!(format_ticket_number(1234567), "SOL-1234567");
+
+        assert_eq!(parse_ticket_number("sol-123"), Some("SOL-000123".to_string()));
+        assert_eq!(parse_ticket_number("SOL-000123"), Some("SOL-000123".to_string()));
+        assert_eq!(parse_ticket_number("SOL-"), None);
+        assert_eq!(parse_ticket_number("0a1b2c3d4e5f"), None);
+    }
+
+    #[test]
+    fn verify_signature_ok() {
+        let payload = "The quick brown fox jumps over the lazy dog".as_bytes();
+        let signature = "sha256=f7bc83f430538424b13298e6aa6fb143ef4d59a14946175997479dbc2d1a3cd8";
+        assert_eq!(verify_signature(payload, signature, "key"), true)
+    }
+
+    #[test]
+    fn verify_signature_fail() {
+        let payload = "The quick brown fox jumps over the lazy cat".as_bytes();
+        let signature = "sha256=f7bc83f430538424b13298e6aa6fb143ef4d59a14946175997479dbc2d1a3cd8";
+        assert_eq!(verify_signature(payload, signature, "key"), false);
+        assert_eq!(verify_signature(payload, "f7bc83f4", "key"), false)
+    }
+
+    #[test]
+    fn template_message_replaces_body() {
+        let message = MessageRequest{
+            system_id: 3,
+            to: vec!["56912345678".to_string()],
+            message_type: "text".to_string(),
+            content: MessageContent { body: Some("Repuesto identificado:\nFiltro de aceite".to_string()), list: None, buttons: None },
+        };
+        let template = MessageTemplate{ name: "part_classified".to_string(), language: "es".to_string(), parameters: vec!["{body}".to_string()] };
+
+        let template_message = to_template_message(&message, &template, "en");
+        assert_eq!(template_message.message_type, "template");
+        assert_eq!(template_message.to, message.to);
+
+        let sent_template: MessageTemplate = serde_json::from_str(template_message.content.body.as_ref().unwrap()).unwrap();
+        assert_eq!(sent_template.language, "en");
+        assert_eq!(sent_template.parameters, vec!["Repuesto identificado: Filtro de aceite".to_string()]);
+    }
+
+    #[test]
+    fn replace_name_with_and_without_profile_name() {
+        assert_eq!(replace_name("Hola {name}, selecciona la marca.", Some("Juan")), "Hola Juan, selecciona la marca.");
+        assert_eq!(replace_name("Hola {name}, selecciona la marca.", None), "Hola, selecciona la marca.");
+        assert_eq!(replace_name("Gracias {name}.", Some(" ")), "Gracias.");
+    }
+}
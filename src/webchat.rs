@@ -0,0 +1,263 @@
+use std::collections::HashMap;
+use std::sync::{Mutex, OnceLock};
+use std::time::{SystemTime, UNIX_EPOCH};
+use actix_ws::{CloseCode, CloseReason, MessageStream, Session};
+use async_trait::async_trait;
+use fizzy_commons::shared_structs::MessageRequest;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use uuid::Uuid;
+use crate::channel::Channel;
+use crate::constants::MessageType;
+use crate::redis::{create_webchat_session, get_inbound_message, save_inbound_message, touch_webchat_session};
+use crate::request_handler::route_inbound;
+use crate::structs::{InboundMessage, ModifiedReference, StandardResponse};
+
+// Web chat users are stored with this prefix in place of the phone number
+pub const WEBCHAT_PREFIX: &str = "webchat:";
+
+// Close code the page receives when its session is rejected, a new one is requested then
+const INVALID_SESSION_CLOSE_CODE: u16 = 4001;
+
+// Message sent by the web chat page
+#[derive(Deserialize)]
+struct WebChatFrame {
+    #[serde(rename = "type")]
+    frame_type: String, // text, list_reply or button_reply
+    text: Option<String>,
+    id: Option<String>,
+}
+
+pub struct WebChatChannel;
+
+#[async_trait(?Send)]
+impl Channel for WebChatChannel {
+    fn name(&self) -> &'static str {
+        "webchat"
+    }
+
+    fn get_message(&self, user_id: &str, message_id: &str) -> Result<InboundMessage, String> {
+        get_inbound_message(user_id, message_id)
+    }
+
+    async fn send(&self, message: &MessageRequest) -> Result<StandardResponse, String> {
+        let mut response = StandardResponse::new();
+
+        for user_id in &message.to {
+            // Sessions are kept by the instance the page is connected to
+            let session = sessions().lock().unwrap().get(user_id).map(|(_, session)| session.clone());
+
+            if session.is_none() {
+                return Err(format!("Web chat session {} not connected", user_id))
+            }
+
+            let message_id = Uuid::new_v4().to_string().replace("-", "");
+            let frame = to_webchat_frame(message, &message_id).to_string();
+            let mut session = session.unwrap();
+
+            if session.text(frame).await.is_err() {
+                return Err(format!("Web chat session {} closed before the message was sent", user_id))
+            }
+
+            response.references.push(ModifiedReference{ system: "WEBCHAT".to_string(), reference: message_id });
+        }
+
+        Ok(response)
+    }
+
+    // There's no whatsapp-manager in between web chat users and this system
+    fn continues_flow(&self) -> bool {
+        true
+    }
+
+    fn has_service_window(&self) -> bool {
+        false
+    }
+}
+
+// Connected sessions by user, along with the connection id
+fn sessions() -> &'static Mutex<HashMap<String, (String, Session)>> {
+    static SESSIONS: OnceLock<Mutex<HashMap<String, (String, Session)>>> = OnceLock::new();
+    SESSIONS.get_or_init(|| Mutex::new(HashMap::new()))
+}
+
+// Message as rendered by the web chat page, lists and buttons carry their choices
+pub fn to_webchat_frame(message: &MessageRequest, message_id: &str) -> Value {
+    let choices = message.content.list.as_ref().map(|list| (&list.title, &list.choices))
+        .or(message.content.buttons.as_ref().map(|buttons| (&buttons.title, &buttons.choices)));
+
+    json!({
+        "id": message_id,
+        "type": message.message_type,
+        "body": message.content.body.clone().unwrap_or_default(),
+        "title": choices.map(|(title, _)| title.clone()),
+        "choices": choices.map(|(_, choices)| choices.iter()
+            .map(|choice| json!({ "id": choice.id, "value": choice.value }))
+            .collect::<Vec<Value>>()),
+    })
+}
+
+// Session ids are issued by this system, so users can't join someone else's conversation
+pub fn issue_session() -> Result<String, String> {
+    let session_id = Uuid::new_v4().to_string().replace("-", "");
+
+    let res = create_webchat_session(&session_id);
+
+    if res.is_err() {
+        return Err(res.unwrap_err())
+    }
+
+    Ok(session_id)
+}
+
+// Only sessions issued by this system are accepted, connecting keeps them from expiring
+pub fn is_valid_session(session_id: &str) -> bool {
+    if session_id.is_empty() || session_id.len() > 64 || !session_id.chars().all(|c| c.is_ascii_alphanumeric()) {
+        return false
+    }
+
+    match touch_webchat_session(session_id) {
+        Ok(issued) => issued,
+        Err(err) => {
+            error!("Error checking web chat session: {}", err);
+            false
+        }
+    }
+}
+
+pub async fn reject_session(session: Session) {
+    let reason = CloseReason{ code: CloseCode::Other(INVALID_SESSION_CLOSE_CODE), description: Some("Invalid session".to_string()) };
+    let _ = session.close(Some(reason)).await;
+}
+
+// Receives the user messages until the page disconnects
+pub async fn run_session(session_id: String, mut session: Session, mut stream: MessageStream) {
+    let user_id = format!("{}{}", WEBCHAT_PREFIX, session_id);
+    let connection_id = Uuid::new_v4().to_string();
+
+    info!("Web chat session {} connected", user_id);
+    sessions().lock().unwrap().insert(user_id.clone(), (connection_id.clone(), session.clone()));
+
+    while let Some(Ok(message)) = stream.recv().await {
+        match message {
+            actix_ws::Message::Text(text) => {
+                let res = handle_frame(&user_id, &text).await;
+
+                if res.is_err() {
+                    error!("Error handling web chat message: {}", res.unwrap_err());
+                }
+            }
+            actix_ws::Message::Ping(bytes) => {
+                if session.pong(&bytes).await.is_err() {
+                    break
+                }
+            }
+            actix_ws::Message::Close(_) => break,
+            _ => {}
+        }
+    }
+
+    // A newer connection of the same user may have replaced this one
+    let mut sessions = sessions().lock().unwrap();
+    if sessions.get(&user_id).map(|(id, _)| *id == connection_id).unwrap_or(false) {
+        sessions.remove(&user_id);
+    }
+    drop(sessions);
+
+    info!("Web chat session {} disconnected", user_id);
+    let _ = session.close(None).await;
+}
+
+async fn handle_frame(user_id: &str, text: &str) -> Result<(), String> {
+    let frame: WebChatFrame = match serde_json::from_str(text) {
+        Ok(frame) => frame,
+        Err(err) => return Err(format!("Invalid web chat message: {}", err)),
+    };
+
+    let message = normalize_frame(user_id, &frame);
+
+    if message.is_err() {
+        return Err(message.unwrap_err())
+    }
+
+    let message = message.unwrap();
+    let res = save_inbound_message(&message);
+
+    if res.is_err() {
+        return Err(res.unwrap_err())
+    }
+
+    let res = route_inbound(user_id, &message.id, None).await;
+
+    if res.is_err() {
+        return Err(format!("{:?}", res.unwrap_err().errors))
+    }
+
+    Ok(())
+}
+
+fn normalize_frame(user_id: &str, frame: &WebChatFrame) -> Result<InboundMessage, String> {
+    let (message_type, content) = match frame.frame_type.as_str() {
+        "text" => (MessageType::PlainText, frame.text.clone()),
+        "list_reply" => (MessageType::ListSelection, frame.id.clone()),
+        "button_reply" => (MessageType::ButtonSelection, frame.id.clone()),
+        frame_type => return Err(format!("Web chat message type {} not supported", frame_type)),
+    };
+
+    if content.is_none() {
+        return Err(format!("Web chat {} message without content", frame.frame_type))
+    }
+
+    let timestamp = match SystemTime::now().duration_since(UNIX_EPOCH) {
+        Ok(n) => n.as_millis() as u64,
+        Err(_) => panic!("SystemTime before UNIX EPOCH!"),
+    };
+
+    Ok(InboundMessage {
+        id: Uuid::new_v4().to_string().replace("-", ""),
+        user_id: user_id.to_string(),
+        message_type,
+        channel_type: frame.frame_type.clone(),
+        content: content.unwrap(),
+        image_id: None,
+        timestamp,
+        contact_name: None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use fizzy_commons::shared_structs::{Choice, ListMessage, MessageContent, MessageRequest};
+    use crate::constants::MessageType;
+    use crate::webchat::{normalize_frame, to_webchat_frame, WebChatFrame};
+
+    #[test]
+    fn webchat_frames_round_trip() {
+        let message = MessageRequest{
+            system_id: 3,
+            to: vec!["webchat:abc".to_string()],
+            message_type: "list".to_string(),
+            content: MessageContent {
+                body: Some("Selecciona la marca del vehiculo.".to_string()),
+                list: Some(ListMessage{
+                    title: "Marcas".to_string(),
+                    choices: vec![Choice{ id: "make-1".to_string(), value: "Toyota".to_string() }],
+                }),
+                buttons: None,
+            },
+        };
+
+        let frame = to_webchat_frame(&message, "1");
+        assert_eq!(frame["type"], "list");
+        assert_eq!(frame["title"], "Marcas");
+        assert_eq!(frame["choices"][0]["id"], "make-1");
+
+        let reply = WebChatFrame{ frame_type: "list_reply".to_string(), text: None, id: Some("make-1".to_string()) };
+        let inbound = normalize_frame("webchat:abc", &reply).unwrap();
+        assert_eq!(inbound.message_type, MessageType::ListSelection);
+        assert_eq!(inbound.content, "make-1");
+
+        let unsupported = WebChatFrame{ frame_type: "image".to_string(), text: None, id: None };
+        assert!(normalize_frame("webchat:abc", &unsupported).is_err());
+    }
+}